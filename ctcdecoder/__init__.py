@@ -1,7 +1,103 @@
 from .ctcdecoder import beam_search as beam_search_native
+from .ctcdecoder import beam_search_batch as beam_search_batch_native
+from .ctcdecoder import greedy_search as greedy_search_native
+from .ctcdecoder import forced_align as forced_align_native
+from .ctcdecoder import ctc_log_likelihood as ctc_log_likelihood_native
+from .ctcdecoder import StreamingDecoder
+from .ctcdecoder import NgramLanguageModel
+from .ctcdecoder import convert_arpa as convert_arpa_native
 import numpy as np
 
-__all__ = ["beam_search"]
+__all__ = ["beam_search", "beam_search_batch", "greedy_search", "forced_align", "ctc_log_likelihood", "StreamingDecoder", "NgramLanguageModel", "convert_arpa"]
 
-def beam_search(probs: np.ndarray, alphabet: str, beam_size: int = 100, lm_model = None, lm_alpha = 0.9, lm_beta = 0.0001):
-    return beam_search_native(probs, alphabet, beam_size, lm_model, lm_alpha, lm_beta)
+def beam_search(probs: np.ndarray, alphabet, beam_size: int = 100, lm_model = None, lm_alpha = 0.9, lm_beta = 0.0001, log_probs: bool = False, beam_cut_threshold: float = 0.0, score_cut: float = float("inf"), frame_stride: float = None, envelope = None, sentencepiece: bool = False, blank_index = 0, word_delimiter: str = None, hotwords = None, hotword_weight: float = 3.0, lexicon = None, unknown_word_penalty: float = None, pronunciations = None):
+    """Returns a list of (text, score, timestamps, confidences, acoustic score, scorer score)
+    hypotheses. The score is the sum of the other two: the log probability of the text and what the
+    language model, hotwords, lexicon or pronunciations add to it.
+
+    Timestamps are the frame index each character was first emitted at, or its time in seconds if
+    `frame_stride` (seconds per frame) is given. Confidences are the probability of each character
+    at that frame.
+
+    `envelope` optionally restricts the i-th character to be emitted at frames in
+    `range(*envelope[i])`; hypotheses can't have more characters than the envelope has windows.
+
+    `alphabet` is either a string with one character per label or a list of label strings, e.g. the
+    vocabulary of a subword model; timestamps and confidences are then per label. With
+    `sentencepiece` set, the "\u2581" word boundary marker in labels is decoded as a space.
+
+    `lm_model` is either an `NgramLanguageModel`, which is scored natively, or any object with a
+    `score(text)` method returning the log probability of `text`. Every prefix the search reaches is
+    scored once, when it is first extended to. Models with a `score_batch(texts)` method returning a
+    list of log probabilities are instead called once per frame, with all the prefixes it reaches.
+    Models that keep their own state (e.g. recurrent ones) can instead provide `initial_state()` and
+    `advance(state, token)`, returning the next state and the log probability of `token`; each
+    prefix keeps its state, which is advanced by the prefix's last label (or, with a
+    `word_delimiter`, the word it completes).
+    For word-level models, pass the label separating words as `word_delimiter` (e.g. " ") so the
+    model only scores complete words, when the delimiter is emitted, and the last word at the end of
+    the input. `NgramLanguageModel`s also score the end of the sentence, and need a `word_delimiter`
+    if (and only if) they are word-level. With a language model, hypotheses score
+    `log p_am + lm_alpha * log p_lm + lm_beta * n` (shallow fusion), where `n` is the number of tokens
+    (or words) the language model scored. `log p_am` is the acoustic score, so hypotheses can be
+    rescored with other weights.
+
+    `hotwords` is an optional list of texts (words or phrases) to favour: every occurrence of one in a
+    hypothesis adds `hotword_weight` to its score, with or without a language model. Scores are natural
+    log probabilities, so the default of 3 makes a hypothesis with a hotword about 20 times as likely.
+
+    `lexicon` is an optional list of words that hypotheses may only be made of, separated by
+    `word_delimiter` (without one, a hypothesis is a single word). With an `unknown_word_penalty`,
+    other words are allowed too, but every one of them takes the penalty off the score.
+
+    `pronunciations` decodes labels such as phonemes to words instead: it is a dict mapping every word
+    to a list of its pronunciations, each a list of labels or a string of labels separated by spaces
+    (e.g. `{"tomato": ["T AH M EY T OW", "T AH M AA T OW"]}`). Hypotheses are then made of words,
+    separated by spaces, with the timestamp of the first label of every word and the confidence of
+    its least confident label. Words are separated by `word_delimiter` or, without one, wherever a
+    pronunciation ends. This can't be combined with `lm_model`, `hotwords` or `lexicon`.
+
+    `blank_index` is the column of the blank label in `probs` (and its position in `alphabet`), either
+    an integer or "last".
+    """
+    return beam_search_native(probs, alphabet, beam_size, lm_model, lm_alpha, lm_beta, log_probs, beam_cut_threshold, score_cut, frame_stride, envelope, sentencepiece, blank_index, word_delimiter, hotwords, hotword_weight, lexicon, unknown_word_penalty, pronunciations)
+
+def beam_search_batch(probs: np.ndarray, alphabet, lengths = None, beam_size: int = 100, lm_model = None, lm_alpha = 0.9, lm_beta = 0.0001, log_probs: bool = False, beam_cut_threshold: float = 0.0, score_cut: float = float("inf"), frame_stride: float = None, sentencepiece: bool = False, blank_index = 0, word_delimiter: str = None, hotwords = None, hotword_weight: float = 3.0, lexicon = None, unknown_word_penalty: float = None):
+    """Decodes a (batch, time, labels) array, only looking at the first `lengths[i]` frames of item i.
+
+    Returns a list of `beam_search` results, one per item. Items are decoded in parallel unless an
+    `lm_model` is given.
+    """
+    if lengths is None:
+        lengths = [probs.shape[1]] * probs.shape[0]
+    return beam_search_batch_native(probs, list(lengths), alphabet, beam_size, lm_model, lm_alpha, lm_beta, log_probs, beam_cut_threshold, score_cut, frame_stride, sentencepiece, blank_index, word_delimiter, hotwords, hotword_weight, lexicon, unknown_word_penalty)
+
+def greedy_search(probs: np.ndarray, alphabet, sentencepiece: bool = False, blank_index = 0, log_probs: bool = False):
+    """Returns the (text, timestamps, confidences) of the most likely label of every frame, with
+    repeats collapsed and blanks removed.
+
+    Timestamps are the frame index each character was emitted at and confidences its probability at
+    that frame, like those of `beam_search`.
+    """
+    return greedy_search_native(probs, alphabet, log_probs, sentencepiece, blank_index)
+
+def forced_align(probs: np.ndarray, alphabet, target: str, log_probs: bool = False, blank_index = 0):
+    """Returns the log probability of the best alignment of `target` and a (start, end, log probability)
+    frame span for each of its labels.
+
+    With a list of label strings as `alphabet`, `target` is split into labels by longest match.
+    """
+    return forced_align_native(probs, alphabet, target, log_probs, blank_index)
+
+def ctc_log_likelihood(probs: np.ndarray, alphabet, target: str, log_probs: bool = False, posteriors: bool = False, blank_index = 0):
+    """Returns the CTC log likelihood of `target` (the negated CTC loss) and, if `posteriors` is set,
+    a (frames, target labels) array with the probability of each frame being aligned to each label.
+    """
+    return ctc_log_likelihood_native(probs, alphabet, target, log_probs, posteriors, blank_index)
+
+def convert_arpa(arpa_path: str, binary_path: str):
+    """Converts an ARPA n-gram model into a compact binary one (a trie with quantized probabilities).
+
+    `NgramLanguageModel` memory-maps binary models, so processes using the same file share its pages.
+    """
+    convert_arpa_native(arpa_path, binary_path)
@@ -0,0 +1,289 @@
+use ndarray::{Array2, ArrayBase, Data, Ix2};
+
+use crate::search::log_sum_exp;
+use crate::vec2d::Vec2D;
+
+#[derive(Clone, Copy, Debug)]
+pub enum AlignmentError {
+    TargetTooLong,
+}
+
+impl std::fmt::Display for AlignmentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AlignmentError::TargetTooLong => {
+                write!(f, "Target can't be aligned (too long for the input?)")
+            }
+        }
+    }
+}
+
+/// The frames a target label occupies in an alignment and the log probability of those frames.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LabelSpan {
+    pub start: usize,
+    pub end: usize,
+    pub score: f32,
+}
+
+/// The target interleaved with blanks, which is the state space CTC alignments walk through.
+///
+/// Returns the column of `probs` for every state.
+fn extended_target(target: &[usize], blank: usize) -> Vec<usize> {
+    let mut extended = Vec::with_capacity(2 * target.len() + 1);
+    extended.push(blank);
+    for &label in target {
+        extended.push(label);
+        extended.push(blank);
+    }
+    extended
+}
+
+/// Whether an alignment can move from state `s - 2` straight to state `s`, skipping a blank.
+///
+/// Blanks sit at the even states of the extended target.
+fn can_skip(extended: &[usize], s: usize) -> bool {
+    s >= 2 && s % 2 == 1 && extended[s] != extended[s - 2]
+}
+
+/// Finds the most likely CTC alignment (Viterbi path) of `target` against `probs`.
+///
+/// `probs` holds per-frame log probabilities with the blank label in column `blank`, and `target`
+/// holds column indices of the labels to align. Returns the log probability of the path and the span of
+/// every target label.
+pub fn viterbi_align<D>(
+    probs: &ArrayBase<D, Ix2>,
+    target: &[usize],
+    blank: usize,
+) -> Result<(f32, Vec<LabelSpan>), AlignmentError>
+where
+    D: Data<Elem = f32>,
+{
+    let extended = extended_target(target, blank);
+    let num_states = extended.len();
+
+    // best[s] is the log probability of the best path ending in state s at the current frame, and
+    // steps[(t, s)] is how many states that path moved forward to reach s at frame t
+    let mut best = vec![f32::NEG_INFINITY; num_states];
+    let mut next_best = vec![f32::NEG_INFINITY; num_states];
+    let mut steps = Vec2D::new(num_states);
+
+    for (t, pr) in probs.outer_iter().enumerate() {
+        steps.add_row_with_value(0_u8);
+        for s in 0..num_states {
+            let (mut score, mut step) = if t == 0 {
+                (if s < 2 { 0.0 } else { f32::NEG_INFINITY }, 0)
+            } else {
+                (best[s], 0)
+            };
+            if t > 0 && s >= 1 && best[s - 1] > score {
+                score = best[s - 1];
+                step = 1;
+            }
+            if t > 0 && can_skip(&extended, s) && best[s - 2] > score {
+                score = best[s - 2];
+                step = 2;
+            }
+            next_best[s] = score + pr[extended[s]];
+            steps[(t, s)] = step;
+        }
+        std::mem::swap(&mut best, &mut next_best);
+    }
+
+    // a path has to finish on the last label or the blank after it
+    let mut state = num_states - 1;
+    if num_states >= 2 && best[num_states - 2] > best[state] {
+        state = num_states - 2;
+    }
+    let total = best[state];
+    if total == f32::NEG_INFINITY || probs.nrows() == 0 {
+        return Err(AlignmentError::TargetTooLong);
+    }
+
+    let mut spans = vec![
+        LabelSpan {
+            start: 0,
+            end: 0,
+            score: 0.0,
+        };
+        target.len()
+    ];
+    for t in (0..probs.nrows()).rev() {
+        if state % 2 == 1 {
+            let span = &mut spans[state / 2];
+            if span.end == 0 {
+                span.end = t + 1;
+            }
+            span.start = t;
+            span.score += probs[[t, extended[state]]];
+        }
+        state -= steps[(t, state)] as usize;
+    }
+
+    Ok((total, spans))
+}
+
+/// Computes the CTC log likelihood of `target` given `probs`, i.e. the negated CTC loss, by summing
+/// over all alignments.
+///
+/// `probs`, `target` and `blank` are as for [`viterbi_align`]. If `posteriors` is set, also returns a
+/// `(frames, target.len())` array with the probability of every frame being aligned to every target
+/// position.
+pub fn forward_backward<D>(
+    probs: &ArrayBase<D, Ix2>,
+    target: &[usize],
+    blank: usize,
+    posteriors: bool,
+) -> Result<(f32, Option<Array2<f32>>), AlignmentError>
+where
+    D: Data<Elem = f32>,
+{
+    let extended = extended_target(target, blank);
+    let num_states = extended.len();
+    let num_frames = probs.nrows();
+    if num_frames == 0 {
+        return Err(AlignmentError::TargetTooLong);
+    }
+
+    // alpha[(t, s)] is the log probability of frames 0..=t ending in state s
+    let mut alpha = Vec2D::new(num_states);
+    for (t, pr) in probs.outer_iter().enumerate() {
+        alpha.add_row_with_value(f32::NEG_INFINITY);
+        for s in 0..num_states {
+            let mut prev = if t == 0 {
+                if s < 2 {
+                    0.0
+                } else {
+                    f32::NEG_INFINITY
+                }
+            } else {
+                alpha[(t - 1, s)]
+            };
+            if t > 0 && s >= 1 {
+                prev = log_sum_exp(prev, alpha[(t - 1, s - 1)]);
+            }
+            if t > 0 && can_skip(&extended, s) {
+                prev = log_sum_exp(prev, alpha[(t - 1, s - 2)]);
+            }
+            alpha[(t, s)] = prev + pr[extended[s]];
+        }
+    }
+
+    let last = num_frames - 1;
+    let mut total = alpha[(last, num_states - 1)];
+    if num_states >= 2 {
+        total = log_sum_exp(total, alpha[(last, num_states - 2)]);
+    }
+    if total == f32::NEG_INFINITY {
+        return Err(AlignmentError::TargetTooLong);
+    }
+    if !posteriors {
+        return Ok((total, None));
+    }
+
+    // beta[s] is the log probability of the frames after the current one given state s
+    let mut beta = vec![f32::NEG_INFINITY; num_states];
+    let mut next_beta = vec![f32::NEG_INFINITY; num_states];
+    beta[num_states - 1] = 0.0;
+    if num_states >= 2 {
+        beta[num_states - 2] = 0.0;
+    }
+    let mut occupancy = Array2::zeros((num_frames, target.len()));
+    for t in (0..num_frames).rev() {
+        for s in (1..num_states).step_by(2) {
+            occupancy[[t, s / 2]] = (alpha[(t, s)] + beta[s] - total).exp();
+        }
+        if t == 0 {
+            break;
+        }
+        for s in 0..num_states {
+            let mut next = beta[s] + probs[[t, extended[s]]];
+            if s + 1 < num_states {
+                next = log_sum_exp(next, beta[s + 1] + probs[[t, extended[s + 1]]]);
+            }
+            if s + 2 < num_states && can_skip(&extended, s + 2) {
+                next = log_sum_exp(next, beta[s + 2] + probs[[t, extended[s + 2]]]);
+            }
+            next_beta[s] = next;
+        }
+        std::mem::swap(&mut beta, &mut next_beta);
+    }
+
+    Ok((total, Some(occupancy)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_viterbi_align() {
+        let probs = array![
+            [0.1f32, 0.8, 0.1],
+            [0.2, 0.7, 0.1],
+            [0.8, 0.1, 0.1],
+            [0.1, 0.7, 0.2],
+            [0.1, 0.2, 0.7],
+            [0.9, 0.05, 0.05],
+        ]
+        .mapv(f32::ln);
+        let (total, spans) = viterbi_align(&probs, &[1, 1, 2], 0).unwrap();
+        let expected = (0.8f32 * 0.7 * 0.8 * 0.7 * 0.7 * 0.9).ln();
+        assert!((total - expected).abs() < 1e-5);
+        let spans: Vec<(usize, usize)> = spans.iter().map(|x| (x.start, x.end)).collect();
+        assert_eq!(spans, vec![(0, 2), (3, 4), (4, 5)]);
+    }
+
+    #[test]
+    fn test_repeats_need_a_blank() {
+        let probs = array![[0.1f32, 0.9], [0.1, 0.9], [0.1, 0.9]].mapv(f32::ln);
+        let (total, spans) = viterbi_align(&probs, &[1, 1], 0).unwrap();
+        assert!((total - (0.9f32 * 0.1 * 0.9).ln()).abs() < 1e-5);
+        assert_eq!((spans[0].start, spans[0].end), (0, 1));
+        assert_eq!((spans[1].start, spans[1].end), (2, 3));
+
+        let result = viterbi_align(&probs.slice(ndarray::s![..2, ..]), &[1, 1], 0);
+        assert!(matches!(result, Err(AlignmentError::TargetTooLong)));
+    }
+
+    #[test]
+    fn test_forward_backward() {
+        // "a" = "a-" + "-a" + "aa"
+        let probs = array![[0.4f32, 0.6], [0.3, 0.7]];
+        let (total, occupancy) = forward_backward(&probs.mapv(f32::ln), &[1], 0, true).unwrap();
+        let p_a = 0.6 * 0.3 + 0.4 * 0.7 + 0.6 * 0.7;
+        assert!((total - f32::ln(p_a)).abs() < 1e-5);
+        let occupancy = occupancy.unwrap();
+        assert!((occupancy[[0, 0]] - (0.6 * 0.3 + 0.6 * 0.7) / p_a).abs() < 1e-5);
+        assert!((occupancy[[1, 0]] - (0.4 * 0.7 + 0.6 * 0.7) / p_a).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_forward_backward_matches_viterbi_on_peaky_input() {
+        let probs = array![
+            [0.001f32, 0.998, 0.001],
+            [0.998, 0.001, 0.001],
+            [0.001, 0.998, 0.001],
+            [0.001, 0.001, 0.998],
+        ]
+        .mapv(f32::ln);
+        let (total, occupancy) = forward_backward(&probs, &[1, 1, 2], 0, true).unwrap();
+        let (best, _) = viterbi_align(&probs, &[1, 1, 2], 0).unwrap();
+        assert!((total - best).abs() < 1e-2);
+        let occupancy = occupancy.unwrap();
+        assert!(occupancy[[0, 0]] > 0.99);
+        assert!(occupancy[[2, 1]] > 0.99);
+        assert!(occupancy[[3, 2]] > 0.99);
+    }
+
+    #[test]
+    fn test_blank_last() {
+        let probs = array![[0.8f32, 0.1, 0.1], [0.1, 0.1, 0.8], [0.7, 0.2, 0.1]].mapv(f32::ln);
+        let (total, spans) = viterbi_align(&probs, &[0, 0], 2).unwrap();
+        assert!((total - (0.8f32 * 0.8 * 0.7).ln()).abs() < 1e-5);
+        assert_eq!((spans[1].start, spans[1].end), (2, 3));
+        let (total, _) = forward_backward(&probs, &[0, 0], 2, false).unwrap();
+        assert!((total - (0.8f32 * 0.8 * 0.7).ln()).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+
+/// The node of the empty word.
+pub const LEXICON_ROOT: usize = 0;
+
+/// A fixed set of words, stored as a trie over their characters so that a word can be checked one
+/// character at a time as it is spelled out.
+pub struct Lexicon {
+    /// The child of every node for every character following it.
+    children: HashMap<(usize, char), usize>,
+    /// Whether the characters leading to every node spell a word.
+    is_word: Vec<bool>,
+}
+
+impl Lexicon {
+    pub fn new<I>(words: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        let mut lexicon = Self {
+            children: HashMap::new(),
+            is_word: vec![false],
+        };
+        for word in words {
+            let mut node = LEXICON_ROOT;
+            for c in word.as_ref().chars() {
+                let next = lexicon.is_word.len();
+                node = *lexicon.children.entry((node, c)).or_insert(next);
+                if node == next {
+                    lexicon.is_word.push(false);
+                }
+            }
+            lexicon.is_word[node] = true;
+        }
+        lexicon
+    }
+
+    /// The node reached by spelling `c` after `node`, if some word continues that way.
+    pub fn child(&self, node: usize, c: char) -> Option<usize> {
+        self.children.get(&(node, c)).copied()
+    }
+
+    /// Whether the characters leading to `node` spell a word (rather than just the start of one).
+    pub fn is_word(&self, node: usize) -> bool {
+        self.is_word[node]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spell(lexicon: &Lexicon, text: &str) -> Option<usize> {
+        text.chars()
+            .try_fold(LEXICON_ROOT, |node, c| lexicon.child(node, c))
+    }
+
+    #[test]
+    fn test_lexicon() {
+        let lexicon = Lexicon::new(&["aspirin", "asp", "ibuprofen"]);
+        assert!(!lexicon.is_word(LEXICON_ROOT));
+        assert!(lexicon.is_word(spell(&lexicon, "asp").unwrap()));
+        assert!(!lexicon.is_word(spell(&lexicon, "aspi").unwrap()));
+        assert!(lexicon.is_word(spell(&lexicon, "aspirin").unwrap()));
+        assert!(lexicon.is_word(spell(&lexicon, "ibuprofen").unwrap()));
+        assert_eq!(spell(&lexicon, "aspirins"), None);
+        assert_eq!(spell(&lexicon, "b"), None);
+    }
+}
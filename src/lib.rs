@@ -1,60 +1,1077 @@
+mod align;
+mod lexicon;
+mod ngram;
+mod pronunciation;
+mod scorer;
+mod search;
 mod tree;
 mod vec2d;
 
-use numpy::array::PyArray2;
-use pyo3::exceptions::{PyAssertionError, PyRuntimeError};
+use align::AlignmentError;
+use lexicon::Lexicon;
+use ndarray::{s, Array2, ArrayView2, ArrayView3, CowArray, Ix2};
+use ngram::{LmError, NgramModel, Unit};
+use numpy::array::{PyArray2, PyArray3};
+use numpy::IntoPyArray;
+use pronunciation::{PronunciationLexicon, PronunciationScorer};
+use pyo3::exceptions::{PyAssertionError, PyOSError, PyRuntimeError, PyValueError};
 
-use pyo3::prelude::{pymodule, PyModule, PyResult, Python};
-use pyo3::types::{PyFloat, PyString};
-use pyo3::{PyAny, PyObject};
-use tree::*;
+use pyo3::prelude::{
+    pyclass, pymethods, pymodule, FromPyObject, IntoPy, Py, PyCell, PyModule, PyObject, PyResult,
+    Python,
+};
+use pyo3::types::{PyDict, PyString};
+use pyo3::{PyAny, PyErr, PyNativeType};
+use rayon::prelude::*;
+use scorer::{HotwordBonus, LengthBonus, LexiconScorer, NgramScorer, NgramState, Scorer, Weighted};
+use search::{BeamSearch, Hypothesis, SearchError};
 
-#[derive(Clone, Copy, Debug)]
-struct SearchPoint {
-    /// The node search should progress from.
-    node: i32,
-    prob: f32,
+impl From<SearchError> for PyErr {
+    fn from(err: SearchError) -> PyErr {
+        PyRuntimeError::new_err(format!("{}", err))
+    }
 }
 
-#[derive(Clone, Copy, Debug)]
-pub enum SearchError {
-    RanOutOfBeam,
-    IncomparableValues,
-    InvalidEnvelope,
+impl From<AlignmentError> for PyErr {
+    fn from(err: AlignmentError) -> PyErr {
+        PyValueError::new_err(format!("{}", err))
+    }
 }
 
-impl std::fmt::Display for SearchError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            SearchError::RanOutOfBeam => {
-                write!(f, "Ran out of search space (beam_cut_threshold too high)")
+impl From<LmError> for PyErr {
+    fn from(err: LmError) -> PyErr {
+        match err {
+            LmError::Io(_) => PyOSError::new_err(format!("{}", err)),
+            LmError::Format { .. } | LmError::Binary(_) => {
+                PyValueError::new_err(format!("{}", err))
             }
-            SearchError::IncomparableValues => {
-                write!(f, "Failed to compare values (NaNs in input?)")
+        }
+    }
+}
+
+/// A Python language model, i.e. an object whose `score(text)` method gives the log probability of
+/// `text`, or whose `score_batch(texts)` method gives the log probabilities of several texts at once.
+///
+/// Every label is scored as it is added, unless there is a `word_delimiter`, in which case words are
+/// scored when the delimiter completes them (and the last one at the end).
+struct PythonLm<'a> {
+    lm: &'a PyAny,
+    batched: bool,
+    alphabet: &'a [String],
+    word_delimiter: Option<usize>,
+}
+
+/// The text of a labelling and the Python language model's score for its first `scored` bytes.
+#[derive(Clone)]
+struct PythonLmState {
+    text: String,
+    scored: usize,
+    score: f32,
+}
+
+impl<'a> PythonLm<'a> {
+    fn new(lm: &'a PyAny, alphabet: &'a [String], word_delimiter: Option<usize>) -> PyResult<Self> {
+        Ok(Self {
+            lm,
+            batched: lm.hasattr("score_batch")?,
+            alphabet,
+            word_delimiter,
+        })
+    }
+
+    /// Scores `texts`, in a single call if the model takes batches.
+    fn score(&self, texts: &[&str]) -> PyResult<Vec<f32>> {
+        if !self.batched {
+            return texts
+                .iter()
+                .map(|&text| self.lm.call_method1("score", (text,))?.extract::<f32>())
+                .collect();
+        }
+        if texts.is_empty() {
+            return Ok(Vec::new());
+        }
+        let scores: Vec<f32> = self
+            .lm
+            .call_method1("score_batch", (texts.to_vec(),))?
+            .extract()?;
+        if scores.len() != texts.len() {
+            return Err(PyValueError::new_err(format!(
+                "Expected score_batch to return {} scores, got {}",
+                texts.len(),
+                scores.len()
+            )));
+        }
+        Ok(scores)
+    }
+}
+
+impl Scorer for PythonLm<'_> {
+    type State = PythonLmState;
+    type Error = PyErr;
+
+    fn initial_state(&mut self) -> PyResult<PythonLmState> {
+        Ok(PythonLmState {
+            text: String::new(),
+            scored: 0,
+            score: self.score(&[""])?[0],
+        })
+    }
+
+    fn extend(&mut self, state: &PythonLmState, label: usize) -> PyResult<(PythonLmState, f32)> {
+        Ok(self.extend_batch(&[(state, label)])?.remove(0))
+    }
+
+    fn extend_batch(
+        &mut self,
+        extensions: &[(&PythonLmState, usize)],
+    ) -> PyResult<Vec<(PythonLmState, f32)>> {
+        let mut extended: Vec<PythonLmState> = extensions
+            .iter()
+            .map(|&(state, label)| PythonLmState {
+                text: format!("{}{}", state.text, self.alphabet[label]),
+                scored: state.scored,
+                score: state.score,
+            })
+            .collect();
+        // labels are scored as they come, or words once the delimiter completes them
+        let complete: Vec<usize> = extensions
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, label))| match self.word_delimiter {
+                Some(delimiter) => *label == delimiter,
+                None => true,
+            })
+            .map(|(i, _)| i)
+            .collect();
+        let texts: Vec<&str> = complete
+            .iter()
+            .map(|&i| extended[i].text.as_str())
+            .collect();
+        let scores = self.score(&texts)?;
+
+        let mut log_probs = vec![0.0; extended.len()];
+        for (&i, score) in complete.iter().zip(scores) {
+            let state = &mut extended[i];
+            log_probs[i] = score - state.score;
+            state.scored = state.text.len();
+            state.score = score;
+        }
+        Ok(extended.into_iter().zip(log_probs).collect())
+    }
+
+    fn finish(&mut self, state: &PythonLmState) -> PyResult<f32> {
+        if state.scored == state.text.len() {
+            return Ok(0.0);
+        }
+        Ok(self.score(&[state.text.as_str()])?[0] - state.score)
+    }
+}
+
+/// A Python language model that keeps a state for every labelling, i.e. an object with an
+/// `initial_state()` method and an `advance(state, token)` method returning the next state and the
+/// log probability of `token`. States are opaque to the search.
+///
+/// Tokens are the texts of labels, or whole words if there is a `word_delimiter` (see `PythonLm`).
+struct StatefulLm<'a> {
+    lm: &'a PyAny,
+    alphabet: &'a [String],
+    word_delimiter: Option<usize>,
+}
+
+/// A stateful Python language model's state for a labelling, and the text of a word not given to it
+/// yet.
+#[derive(Clone)]
+struct StatefulLmState {
+    state: PyObject,
+    word: String,
+}
+
+impl StatefulLm<'_> {
+    fn advance(&self, state: &PyObject, token: &str) -> PyResult<(PyObject, f32)> {
+        let state = state.clone_ref(self.lm.py());
+        self.lm.call_method1("advance", (state, token))?.extract()
+    }
+}
+
+impl Scorer for StatefulLm<'_> {
+    type State = StatefulLmState;
+    type Error = PyErr;
+
+    fn initial_state(&mut self) -> PyResult<StatefulLmState> {
+        Ok(StatefulLmState {
+            state: self.lm.call_method0("initial_state")?.into(),
+            word: String::new(),
+        })
+    }
+
+    fn extend(
+        &mut self,
+        state: &StatefulLmState,
+        label: usize,
+    ) -> PyResult<(StatefulLmState, f32)> {
+        let token = match self.word_delimiter {
+            Some(delimiter) if delimiter != label => {
+                let state = StatefulLmState {
+                    state: state.state.clone(),
+                    word: format!("{}{}", state.word, self.alphabet[label]),
+                };
+                return Ok((state, 0.0));
             }
-            // TODO: document envelope constraints
-            SearchError::InvalidEnvelope => write!(f, "Invalid envelope values"),
+            Some(_) if state.word.is_empty() => return Ok((state.clone(), 0.0)),
+            Some(_) => state.word.as_str(),
+            None => self.alphabet[label].as_str(),
+        };
+        let (lm_state, log_prob) = self.advance(&state.state, token)?;
+        let state = StatefulLmState {
+            state: lm_state,
+            word: String::new(),
+        };
+        Ok((state, log_prob))
+    }
+
+    fn finish(&mut self, state: &StatefulLmState) -> PyResult<f32> {
+        if state.word.is_empty() {
+            return Ok(0.0);
         }
+        let (_, log_prob) = self.advance(&state.state, &state.word)?;
+        Ok(log_prob)
     }
 }
 
-fn get_lm_prob(
-    path: &str,
-    i: usize,
-    lm_model: Option<&PyAny>,
+/// The scorers of a search with language model `L`: the language model, with a bonus for every
+/// token (or word) it scores, a bonus for every hotword and the lexicon.
+type Scorers<'a, L> = (
+    Option<(Weighted<L>, Weighted<LengthBonus>)>,
+    (
+        Option<Weighted<HotwordBonus<'a>>>,
+        Option<LexiconScorer<'a>>,
+    ),
+);
+
+/// The state of [`Scorers`] with a language model whose state is `S`.
+type ScorersState<S> = (
+    Option<(S, <LengthBonus as Scorer>::State)>,
+    (
+        Option<<HotwordBonus<'static> as Scorer>::State>,
+        Option<<LexiconScorer<'static> as Scorer>::State>,
+    ),
+);
+
+/// What the scorers of a search are set up with, apart from the language model.
+#[derive(Clone, Copy)]
+struct ScorerConfig<'a> {
+    alphabet: &'a [String],
     lm_alpha: f32,
     lm_beta: f32,
-) -> PyResult<f32> {
-    if let Some(lm) = lm_model {
-        Ok(lm_alpha
-            * (lm
-                .call_method1("score", (path,))?
-                .downcast::<PyFloat>()?
-                .value() as f32)
-                .exp()
-            + lm_beta * (i as f32))
+    word_delimiter: Option<usize>,
+    hotwords: Option<&'a [String]>,
+    hotword_weight: f32,
+    lexicon: Option<&'a Lexicon>,
+    unknown_word_penalty: Option<f32>,
+}
+
+impl<'a> ScorerConfig<'a> {
+    /// Combines `lm` with the other scorers. Labellings score
+    /// `log p_am + lm_alpha * log p_lm + lm_beta * n` (shallow fusion), where `n` is the number of
+    /// tokens (or words) the language model scored, plus `hotword_weight` for every hotword in them,
+    /// and only spell words of the lexicon if there is one.
+    fn scorers<L: Scorer>(&self, lm: Option<L>) -> Scorers<'a, L> {
+        let lm = lm.map(|lm| {
+            (
+                Weighted::new(lm, self.lm_alpha),
+                Weighted::new(LengthBonus::new(self.word_delimiter), self.lm_beta),
+            )
+        });
+        let hotwords = self.hotwords.map(|hotwords| {
+            Weighted::new(
+                HotwordBonus::new(self.alphabet, hotwords),
+                self.hotword_weight,
+            )
+        });
+        let lexicon = self.lexicon.map(|lexicon| {
+            LexiconScorer::new(
+                lexicon,
+                self.alphabet,
+                self.word_delimiter,
+                self.unknown_word_penalty,
+            )
+        });
+        (lm, (hotwords, lexicon))
+    }
+}
+
+/// A language model implemented in Python, which the search calls back into.
+trait PyLm: Scorer<Error = PyErr> {
+    /// The search of a streaming decoder with the model, whose Python object is `lm_model`.
+    fn streaming(
+        search: BeamSearch<ScorersState<Self::State>>,
+        lm_model: PyObject,
+    ) -> StreamingSearch;
+}
+
+impl PyLm for PythonLm<'_> {
+    fn streaming(
+        search: BeamSearch<ScorersState<PythonLmState>>,
+        lm_model: PyObject,
+    ) -> StreamingSearch {
+        StreamingSearch::Python(search, lm_model)
+    }
+}
+
+impl PyLm for StatefulLm<'_> {
+    fn streaming(
+        search: BeamSearch<ScorersState<StatefulLmState>>,
+        lm_model: PyObject,
+    ) -> StreamingSearch {
+        StreamingSearch::Stateful(search, lm_model)
+    }
+}
+
+/// A search that can be run with every kind of language model, see [`run_with_lm`].
+trait LmSearch {
+    type Output;
+
+    /// Runs the search with `scorers`, whose language model `lm_model` is implemented in Python.
+    fn run<L: PyLm>(self, lm_model: &PyAny, scorers: Scorers<'_, L>) -> PyResult<Self::Output>;
+
+    /// Runs the search with `scorers`, which don't call into Python, so other threads can run
+    /// meanwhile. `lm_model` is the native language model, if there is one.
+    fn run_native(
+        self,
+        py: Python<'_>,
+        lm_model: Option<&PyCell<NgramLanguageModel>>,
+        scorers: Scorers<'_, NgramScorer<'_>>,
+    ) -> PyResult<Self::Output>;
+}
+
+/// Runs `search` with the scorers of `config` and the language model given from Python, which is
+/// either a native `NgramLanguageModel`, a stateful model with an `advance` method or one that
+/// scores text (see `PythonLm`).
+fn run_with_lm<'a, T: LmSearch>(
+    py: Python<'_>,
+    lm_model: Option<&'a PyAny>,
+    config: ScorerConfig<'a>,
+    search: T,
+) -> PyResult<T::Output> {
+    let native_lm = lm_model.and_then(|x| x.downcast::<PyCell<NgramLanguageModel>>().ok());
+    match (lm_model, native_lm) {
+        (Some(lm_model), None) if lm_model.hasattr("advance")? => {
+            let lm = StatefulLm {
+                lm: lm_model,
+                alphabet: config.alphabet,
+                word_delimiter: config.word_delimiter,
+            };
+            search.run(lm_model, config.scorers(Some(lm)))
+        }
+        (Some(lm_model), None) => {
+            let lm = PythonLm::new(lm_model, config.alphabet, config.word_delimiter)?;
+            search.run(lm_model, config.scorers(Some(lm)))
+        }
+        (_, native_lm) => {
+            let model = native_lm.map(PyCell::borrow);
+            if let Some(model) = &model {
+                check_lm_unit(&model.model, config.word_delimiter)?;
+            }
+            let lm = model
+                .as_ref()
+                .map(|x| NgramScorer::new(&x.model, config.alphabet, config.word_delimiter));
+            search.run_native(py, native_lm, config.scorers(lm))
+        }
+    }
+}
+
+/// Checks that whether words are delimited matches the unit of a native n-gram model: word models
+/// can only score complete words, and character models score every label.
+fn check_lm_unit(model: &NgramModel, word_delimiter: Option<usize>) -> PyResult<()> {
+    match (model.unit(), word_delimiter) {
+        (Unit::Words, None) => Err(PyValueError::new_err(
+            "A word-level language model needs a word delimiter",
+        )),
+        (Unit::Characters { .. }, Some(_)) => Err(PyValueError::new_err(
+            "A character-level language model can't be used with a word delimiter",
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Gets the text of every label from an alphabet given either as a string with one character per
+/// label or as a list of label strings (tokens).
+///
+/// With `sentencepiece` set, SentencePiece's "\u{2581}" word boundary marker is turned into a space.
+fn get_alphabet(alphabet: &PyAny, sentencepiece: bool) -> PyResult<Vec<String>> {
+    let labels: Vec<String> = if let Ok(alphabet) = alphabet.downcast::<PyString>() {
+        alphabet.to_str()?.chars().map(String::from).collect()
+    } else {
+        alphabet.extract()?
+    };
+    if sentencepiece {
+        Ok(labels
+            .into_iter()
+            .map(|label| label.replace('\u{2581}', " "))
+            .collect())
+    } else {
+        Ok(labels)
+    }
+}
+
+/// When the labels of a hypothesis were emitted, as frame indices or, given a frame stride, times
+/// in seconds.
+enum Timestamps {
+    Frames(Vec<usize>),
+    Seconds(Vec<f64>),
+}
+
+impl IntoPy<PyObject> for Timestamps {
+    fn into_py(self, py: Python<'_>) -> PyObject {
+        match self {
+            Timestamps::Frames(frames) => frames.into_py(py),
+            Timestamps::Seconds(times) => times.into_py(py),
+        }
+    }
+}
+
+/// A hypothesis as it is returned to Python.
+type PyHypothesis = (String, f32, Timestamps, Vec<f32>, f32, f32);
+
+/// Converts hypotheses for Python: with `sentencepiece` set, the space a word boundary marker leaves
+/// at the start of every text is dropped, and with a `frame_stride` (seconds per frame), frames are
+/// turned into times.
+fn to_py_hypotheses(
+    hypotheses: Vec<Hypothesis>,
+    sentencepiece: bool,
+    frame_stride: Option<f64>,
+) -> Vec<PyHypothesis> {
+    hypotheses
+        .into_iter()
+        .map(
+            |(text, score, frames, confidences, probability, scorer_score)| {
+                let text = if sentencepiece {
+                    text.trim_start_matches(' ').to_owned()
+                } else {
+                    text
+                };
+                let timestamps = match frame_stride {
+                    Some(stride) => {
+                        Timestamps::Seconds(frames.iter().map(|&x| x as f64 * stride).collect())
+                    }
+                    None => Timestamps::Frames(frames),
+                };
+                (
+                    text,
+                    score,
+                    timestamps,
+                    confidences,
+                    probability,
+                    scorer_score,
+                )
+            },
+        )
+        .collect()
+}
+
+/// Checks that `beam_cut_threshold` is a probability below 1 and that `score_cut` is a
+/// non-negative log probability difference (infinity disabling it).
+fn check_cuts(beam_cut_threshold: f32, score_cut: f32) -> PyResult<()> {
+    if !(0.0..1.0).contains(&beam_cut_threshold) {
+        return Err(PyValueError::new_err(format!(
+            "Expected beam cut threshold to be in [0, 1), got {}",
+            beam_cut_threshold
+        )));
+    }
+    if score_cut.is_nan() || score_cut < 0.0 {
+        return Err(PyValueError::new_err(format!(
+            "Expected score cut to be non-negative, got {}",
+            score_cut
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that `alphabet` has labels, at least the blank.
+fn check_alphabet_not_empty(alphabet: &[String]) -> PyResult<()> {
+    if alphabet.is_empty() {
+        return Err(PyAssertionError::new_err(
+            "Expected alphabet to contain at least the blank label",
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that the label dimension of `probs` matches the labels of `alphabet` (blank included).
+fn check_alphabet(num_labels: usize, alphabet: &[String]) -> PyResult<()> {
+    check_alphabet_not_empty(alphabet)?;
+    let alphabet_size = alphabet.len();
+    if num_labels != alphabet_size {
+        return Err(PyAssertionError::new_err(format!(
+            "Expected probs label dimension ({}) == alphabet size ({})",
+            num_labels, alphabet_size
+        )));
+    }
+    Ok(())
+}
+
+/// The blank index given from Python: a column (negative ones counting from the end) or "last".
+#[derive(FromPyObject)]
+enum BlankIndex {
+    Column(isize),
+    Name(String),
+}
+
+/// Gets the column of the blank label from the blank index given from Python.
+fn get_blank(blank_index: &BlankIndex, alphabet_size: usize) -> PyResult<usize> {
+    let index = match blank_index {
+        BlankIndex::Name(name) if name == "last" => alphabet_size as isize - 1,
+        BlankIndex::Name(name) => {
+            return Err(PyValueError::new_err(format!(
+                "Expected blank index to be an integer or \"last\", got {:?}",
+                name
+            )))
+        }
+        &BlankIndex::Column(index) if index < 0 => alphabet_size as isize + index,
+        &BlankIndex::Column(index) => index,
+    };
+    if index < 0 || index as usize >= alphabet_size {
+        return Err(PyValueError::new_err(format!(
+            "Blank index {} is out of range for an alphabet of size {}",
+            index, alphabet_size
+        )));
+    }
+    Ok(index as usize)
+}
+
+/// Finds the label that delimits words for word-level language model scoring.
+fn get_word_delimiter(
+    alphabet: &[String],
+    word_delimiter: Option<&str>,
+) -> PyResult<Option<usize>> {
+    match word_delimiter {
+        Some(delimiter) => match alphabet.iter().position(|x| x == delimiter) {
+            Some(label) => Ok(Some(label)),
+            None => Err(PyValueError::new_err(format!(
+                "Word delimiter {:?} is not in the alphabet",
+                delimiter
+            ))),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Builds a pronunciation lexicon from a dict mapping words to their pronunciations. Every
+/// pronunciation is either a list of labels or a string of labels separated by spaces, and a word
+/// with a single pronunciation can have it instead of the list.
+fn get_pronunciations(
+    alphabet: &[String],
+    blank: usize,
+    pronunciations: &PyDict,
+) -> PyResult<PronunciationLexicon> {
+    let mut entries = Vec::new();
+    for (word, word_pronunciations) in pronunciations.iter() {
+        let word: String = word.extract()?;
+        let word_pronunciations: Vec<&PyAny> = match word_pronunciations.downcast::<PyString>() {
+            Ok(_) => vec![word_pronunciations],
+            Err(_) => word_pronunciations.extract()?,
+        };
+        for pronunciation in word_pronunciations {
+            let labels: Vec<String> = match pronunciation.downcast::<PyString>() {
+                Ok(pronunciation) => pronunciation
+                    .to_str()?
+                    .split_whitespace()
+                    .map(String::from)
+                    .collect(),
+                Err(_) => pronunciation.extract()?,
+            };
+            if labels.is_empty() {
+                return Err(PyValueError::new_err(format!(
+                    "Pronunciation of {:?} is empty",
+                    word
+                )));
+            }
+            let labels: Vec<usize> = labels
+                .iter()
+                .map(|label| {
+                    alphabet
+                        .iter()
+                        .enumerate()
+                        .position(|(i, x)| i != blank && x == label)
+                        .ok_or_else(|| {
+                            PyValueError::new_err(format!(
+                                "Label {:?} of the pronunciation of {:?} is not in the alphabet",
+                                label, word
+                            ))
+                        })
+                })
+                .collect::<PyResult<_>>()?;
+            entries.push((word.clone(), labels));
+        }
+    }
+    Ok(PronunciationLexicon::new(entries))
+}
+
+/// Splits `target` into labels of `alphabet`, returning their columns in `probs`.
+///
+/// The longest matching label is taken at every step, so token alphabets are handled too.
+fn encode_target(alphabet: &[String], blank: usize, target: &str) -> PyResult<Vec<usize>> {
+    let mut labels = Vec::new();
+    let mut rest = target;
+    while !rest.is_empty() {
+        let label = alphabet
+            .iter()
+            .enumerate()
+            .filter(|&(label, text)| {
+                label != blank && !text.is_empty() && rest.starts_with(text.as_str())
+            })
+            .max_by_key(|(_, text)| text.len())
+            .map(|(label, _)| label)
+            .ok_or_else(|| {
+                PyValueError::new_err(format!("Can't match {:?} with the alphabet", rest))
+            })?;
+        rest = &rest[alphabet[label].len()..];
+        labels.push(label);
+    }
+    Ok(labels)
+}
+
+/// A `LabelSpan` as Python gets it: its first frame, the frame after its last one and its log
+/// probability.
+type Span = (usize, usize, f32);
+
+/// The search works in log space, so only convert if we were given raw probabilities.
+fn to_log_probs(probs: ArrayView2<'_, f32>, log_probs: bool) -> CowArray<'_, f32, Ix2> {
+    if log_probs {
+        probs.into()
     } else {
-        Ok(0_f32)
+        probs.mapv(f32::ln).into()
+    }
+}
+
+/// An n-gram language model that is scored without calling back into Python.
+///
+/// Models can be in ARPA format or in the binary format written by `convert_arpa`, which is mapped
+/// into memory instead of being read.
+#[pyclass]
+struct NgramLanguageModel {
+    model: NgramModel,
+}
+
+#[pymethods]
+impl NgramLanguageModel {
+    #[new]
+    #[args(unit = "\"char\"", space_token = "\"<space>\"")]
+    fn new(py: Python<'_>, path: &str, unit: &str, space_token: &str) -> PyResult<Self> {
+        let unit = match unit {
+            "char" => Unit::Characters {
+                space: space_token.to_owned(),
+            },
+            "word" => Unit::Words,
+            _ => {
+                return Err(PyValueError::new_err(format!(
+                    "Expected unit to be \"char\" or \"word\", got {:?}",
+                    unit
+                )))
+            }
+        };
+        let model = py.allow_threads(|| NgramModel::open(path, unit))?;
+        Ok(Self { model })
+    }
+
+    /// The log probability of `text` at the start of a sentence.
+    fn score(&self, text: &str) -> f32 {
+        self.model.score(text, false)
+    }
+
+    #[getter]
+    fn order(&self) -> usize {
+        self.model.order()
+    }
+}
+
+/// The search of a streaming decoder, together with the language model it was set up with.
+enum StreamingSearch {
+    Python(BeamSearch<ScorersState<PythonLmState>>, PyObject),
+    Stateful(BeamSearch<ScorersState<StatefulLmState>>, PyObject),
+    Native(
+        BeamSearch<ScorersState<NgramState>>,
+        Option<Py<NgramLanguageModel>>,
+    ),
+}
+
+impl StreamingSearch {
+    fn hypotheses(&self) -> Vec<Hypothesis> {
+        match self {
+            StreamingSearch::Python(search, _) => search.hypotheses(),
+            StreamingSearch::Stateful(search, _) => search.hypotheses(),
+            StreamingSearch::Native(search, _) => search.hypotheses(),
+        }
+    }
+
+    fn reset(&mut self) {
+        match self {
+            StreamingSearch::Python(search, _) => search.reset(),
+            StreamingSearch::Stateful(search, _) => search.reset(),
+            StreamingSearch::Native(search, _) => search.reset(),
+        }
+    }
+}
+
+/// Sets up the search of a streaming decoder.
+struct NewStreamingSearch<'a> {
+    alphabet: &'a [String],
+    blank: usize,
+    beam_size: usize,
+    beam_cut_threshold: f32,
+    score_cut: f32,
+}
+
+impl NewStreamingSearch<'_> {
+    fn search<T: Scorer>(&self, scorers: &mut T) -> Result<BeamSearch<T::State>, T::Error> {
+        Ok(BeamSearch::new(
+            self.alphabet,
+            self.blank,
+            self.beam_size,
+            self.beam_cut_threshold,
+            self.score_cut,
+            scorers.initial_state()?,
+        ))
+    }
+}
+
+impl LmSearch for NewStreamingSearch<'_> {
+    type Output = StreamingSearch;
+
+    fn run<L: PyLm>(self, lm_model: &PyAny, mut scorers: Scorers<'_, L>) -> PyResult<Self::Output> {
+        Ok(L::streaming(self.search(&mut scorers)?, lm_model.into()))
+    }
+
+    fn run_native(
+        self,
+        _py: Python<'_>,
+        lm_model: Option<&PyCell<NgramLanguageModel>>,
+        mut scorers: Scorers<'_, NgramScorer<'_>>,
+    ) -> PyResult<Self::Output> {
+        Ok(StreamingSearch::Native(
+            self.search(&mut scorers)?,
+            lm_model.map(Py::from),
+        ))
+    }
+}
+
+/// A beam search that is fed frames in chunks as they become available.
+#[pyclass]
+struct StreamingDecoder {
+    search: StreamingSearch,
+    alphabet: Vec<String>,
+    sentencepiece: bool,
+    lm_alpha: f32,
+    lm_beta: f32,
+    word_delimiter: Option<usize>,
+    hotwords: Option<Vec<String>>,
+    hotword_weight: f32,
+    lexicon: Option<Lexicon>,
+    unknown_word_penalty: Option<f32>,
+    log_probs: bool,
+    frame_stride: Option<f64>,
+}
+
+impl StreamingDecoder {
+    /// Feeds `chunk` into the search, or finishes it if there is none, calling the language model
+    /// the decoder was set up with.
+    fn step(&mut self, py: Python<'_>, chunk: Option<Array2<f32>>) -> PyResult<()> {
+        let config = ScorerConfig {
+            alphabet: &self.alphabet,
+            lm_alpha: self.lm_alpha,
+            lm_beta: self.lm_beta,
+            word_delimiter: self.word_delimiter,
+            hotwords: self.hotwords.as_deref(),
+            hotword_weight: self.hotword_weight,
+            lexicon: self.lexicon.as_ref(),
+            unknown_word_penalty: self.unknown_word_penalty,
+        };
+        match &mut self.search {
+            StreamingSearch::Python(search, lm_model) => {
+                let lm =
+                    PythonLm::new(lm_model.as_ref(py), config.alphabet, config.word_delimiter)?;
+                let mut scorers = config.scorers(Some(lm));
+                match chunk {
+                    Some(chunk) => search.advance(&chunk, &mut scorers),
+                    None => search.finish(&mut scorers),
+                }
+            }
+            StreamingSearch::Stateful(search, lm_model) => {
+                let lm = StatefulLm {
+                    lm: lm_model.as_ref(py),
+                    alphabet: config.alphabet,
+                    word_delimiter: config.word_delimiter,
+                };
+                let mut scorers = config.scorers(Some(lm));
+                match chunk {
+                    Some(chunk) => search.advance(&chunk, &mut scorers),
+                    None => search.finish(&mut scorers),
+                }
+            }
+            StreamingSearch::Native(search, lm_model) => {
+                let lm_model = lm_model.as_ref().map(|x| x.borrow(py));
+                let lm = lm_model
+                    .as_ref()
+                    .map(|x| NgramScorer::new(&x.model, config.alphabet, config.word_delimiter));
+                let mut scorers = config.scorers(lm);
+                py.allow_threads(|| match chunk {
+                    Some(chunk) => search.advance(&chunk, &mut scorers),
+                    None => search.finish(&mut scorers),
+                })
+                .map_err(PyErr::from)
+            }
+        }
+    }
+}
+
+#[pymethods]
+impl StreamingDecoder {
+    #[new]
+    #[args(
+        beam_size = "100",
+        lm_model = "None",
+        lm_alpha = "0.9",
+        lm_beta = "0.0001",
+        log_probs = "false",
+        beam_cut_threshold = "0.0",
+        score_cut = "f32::INFINITY",
+        sentencepiece = "false",
+        blank_index = "BlankIndex::Column(0)",
+        word_delimiter = "None",
+        hotwords = "None",
+        hotword_weight = "3.0",
+        lexicon = "None",
+        unknown_word_penalty = "None",
+        frame_stride = "None"
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        py: Python<'_>,
+        alphabet: &PyAny,
+        beam_size: usize,
+        lm_model: Option<PyObject>,
+        lm_alpha: f32,
+        lm_beta: f32,
+        log_probs: bool,
+        beam_cut_threshold: f32,
+        score_cut: f32,
+        sentencepiece: bool,
+        blank_index: BlankIndex,
+        word_delimiter: Option<String>,
+        hotwords: Option<Vec<String>>,
+        hotword_weight: f32,
+        lexicon: Option<Vec<String>>,
+        unknown_word_penalty: Option<f32>,
+        frame_stride: Option<f64>,
+    ) -> PyResult<Self> {
+        let alphabet = get_alphabet(alphabet, sentencepiece)?;
+        check_alphabet_not_empty(&alphabet)?;
+        check_cuts(beam_cut_threshold, score_cut)?;
+        let blank = get_blank(&blank_index, alphabet.len())?;
+        let word_delimiter = get_word_delimiter(&alphabet, word_delimiter.as_deref())?;
+        let lexicon = lexicon.map(Lexicon::new);
+        let config = ScorerConfig {
+            alphabet: &alphabet,
+            lm_alpha,
+            lm_beta,
+            word_delimiter,
+            hotwords: hotwords.as_deref(),
+            hotword_weight,
+            lexicon: lexicon.as_ref(),
+            unknown_word_penalty,
+        };
+        let search = run_with_lm(
+            py,
+            lm_model.as_ref().map(|x| x.as_ref(py)),
+            config,
+            NewStreamingSearch {
+                alphabet: &alphabet,
+                blank,
+                beam_size,
+                beam_cut_threshold,
+                score_cut,
+            },
+        )?;
+        Ok(Self {
+            search,
+            alphabet,
+            sentencepiece,
+            lm_alpha,
+            lm_beta,
+            word_delimiter,
+            hotwords,
+            hotword_weight,
+            lexicon,
+            unknown_word_penalty,
+            log_probs,
+            frame_stride,
+        })
+    }
+
+    /// Appends a (frames, labels) chunk of posteriors to the input. If decoding the chunk fails, the
+    /// input so far is dropped and the decoder starts over.
+    fn feed(&mut self, py: Python<'_>, chunk: &PyArray2<f32>) -> PyResult<()> {
+        check_alphabet(chunk.shape()[1], &self.alphabet)?;
+        let chunk = unsafe { chunk.as_array() };
+        // copied, so it can't change under us if the GIL is released
+        let chunk = to_log_probs(chunk, self.log_probs).into_owned();
+        let fed = self.step(py, Some(chunk));
+        if fed.is_err() {
+            // a failed search is left in no state worth continuing from
+            self.search.reset();
+        }
+        fed
+    }
+
+    /// The best hypotheses for the input so far, in the same format as `beam_search`.
+    fn partial(&self) -> Vec<PyHypothesis> {
+        to_py_hypotheses(
+            self.search.hypotheses(),
+            self.sentencepiece,
+            self.frame_stride,
+        )
+    }
+
+    /// The final hypotheses for the input, after which the decoder starts over.
+    fn finalize(&mut self, py: Python<'_>) -> PyResult<Vec<PyHypothesis>> {
+        let finished = self.step(py, None);
+        let results = self.search.hypotheses();
+        self.search.reset();
+        finished?;
+        Ok(to_py_hypotheses(
+            results,
+            self.sentencepiece,
+            self.frame_stride,
+        ))
+    }
+}
+
+/// A beam search over a (frames, labels) array of log probabilities.
+struct Search<'a> {
+    probs: CowArray<'a, f32, Ix2>,
+    alphabet: &'a [String],
+    blank: usize,
+    beam_size: usize,
+    beam_cut_threshold: f32,
+    score_cut: f32,
+    envelope: Option<&'a [(usize, usize)]>,
+}
+
+impl Search<'_> {
+    fn search<T: Scorer>(&self, scorers: &mut T) -> Result<Vec<Hypothesis>, T::Error> {
+        search::beam_search(
+            &self.probs,
+            self.alphabet,
+            self.blank,
+            self.beam_size,
+            self.beam_cut_threshold,
+            self.score_cut,
+            self.envelope,
+            scorers,
+        )
+    }
+}
+
+impl LmSearch for Search<'_> {
+    type Output = Vec<Hypothesis>;
+
+    fn run<L: PyLm>(
+        self,
+        _lm_model: &PyAny,
+        mut scorers: Scorers<'_, L>,
+    ) -> PyResult<Self::Output> {
+        self.search(&mut scorers)
+    }
+
+    fn run_native(
+        self,
+        py: Python<'_>,
+        _lm_model: Option<&PyCell<NgramLanguageModel>>,
+        mut scorers: Scorers<'_, NgramScorer<'_>>,
+    ) -> PyResult<Self::Output> {
+        // the input is copied first so it can't change under us while the GIL is released
+        let search = Search {
+            probs: self.probs.into_owned().into(),
+            ..self
+        };
+        Ok(py.allow_threads(|| search.search(&mut scorers))?)
+    }
+}
+
+/// Beam searches over the first `lengths[i]` frames of every item i of a (batch, frames, labels)
+/// array.
+struct BatchSearch<'a> {
+    probs: ArrayView3<'a, f32>,
+    lengths: &'a [usize],
+    log_probs: bool,
+    alphabet: &'a [String],
+    blank: usize,
+    beam_size: usize,
+    beam_cut_threshold: f32,
+    score_cut: f32,
+}
+
+impl BatchSearch<'_> {
+    fn items(&self) -> Vec<ArrayView2<'_, f32>> {
+        self.probs
+            .outer_iter()
+            .zip(self.lengths)
+            .map(|(item, &length)| item.slice_move(s![..length, ..]))
+            .collect()
+    }
+
+    fn search<T: Scorer>(
+        &self,
+        item: ArrayView2<'_, f32>,
+        scorers: &mut T,
+    ) -> Result<Vec<Hypothesis>, T::Error> {
+        search::beam_search(
+            &to_log_probs(item, self.log_probs),
+            self.alphabet,
+            self.blank,
+            self.beam_size,
+            self.beam_cut_threshold,
+            self.score_cut,
+            None,
+            scorers,
+        )
+    }
+}
+
+impl LmSearch for BatchSearch<'_> {
+    type Output = Vec<Vec<Hypothesis>>;
+
+    fn run<L: PyLm>(
+        self,
+        _lm_model: &PyAny,
+        mut scorers: Scorers<'_, L>,
+    ) -> PyResult<Self::Output> {
+        // the language model needs the GIL, so there is no point in spreading the work
+        self.items()
+            .into_iter()
+            .map(|item| self.search(item, &mut scorers))
+            .collect()
+    }
+
+    fn run_native(
+        self,
+        py: Python<'_>,
+        _lm_model: Option<&PyCell<NgramLanguageModel>>,
+        scorers: Scorers<'_, NgramScorer<'_>>,
+    ) -> PyResult<Self::Output> {
+        // copy the input so it can't change under us while the GIL is released
+        let probs = self.probs.to_owned();
+        let search = BatchSearch {
+            probs: probs.view(),
+            ..self
+        };
+        Ok(py.allow_threads(|| {
+            search
+                .items()
+                .into_par_iter()
+                .map_with(scorers, |scorers, item| search.search(item, scorers))
+                .collect::<Result<_, SearchError>>()
+        })?)
     }
 }
 
@@ -62,134 +1079,249 @@ fn get_lm_prob(
 fn ctcdecoder(_py: Python<'_>, _m: &PyModule) -> PyResult<()> {
     #[pyfn(_m)]
     #[pyo3(name = "beam_search")]
+    #[allow(clippy::too_many_arguments)]
     fn beam_search<'py>(
-        _py: Python<'py>,
+        py: Python<'py>,
         probs: &PyArray2<f32>,
-        alphabet: &PyString,
+        alphabet: &PyAny,
         beam_size: usize,
         lm_model: Option<&PyAny>,
         lm_alpha: f32,
         lm_beta: f32,
-    ) -> PyResult<Vec<(String, f32)>> {
-        assert_eq!(
-            probs.shape().len(),
-            2,
-            "Expected 2d array, got {}",
-            probs.shape().len()
-        );
-
-        let alphabet = alphabet.to_str()?;
+        log_probs: bool,
+        beam_cut_threshold: f32,
+        score_cut: f32,
+        frame_stride: Option<f64>,
+        envelope: Option<Vec<(usize, usize)>>,
+        sentencepiece: bool,
+        blank_index: BlankIndex,
+        word_delimiter: Option<&str>,
+        hotwords: Option<Vec<String>>,
+        hotword_weight: f32,
+        lexicon: Option<Vec<String>>,
+        unknown_word_penalty: Option<f32>,
+        pronunciations: Option<&PyDict>,
+    ) -> PyResult<Vec<PyHypothesis>> {
+        let alphabet = get_alphabet(alphabet, sentencepiece)?;
+        check_alphabet(probs.shape()[1], &alphabet)?;
+        check_cuts(beam_cut_threshold, score_cut)?;
+        let blank = get_blank(&blank_index, alphabet.len())?;
+        let delimiter_label = get_word_delimiter(&alphabet, word_delimiter)?;
+        let lexicon = lexicon.map(Lexicon::new);
+        let config = ScorerConfig {
+            alphabet: &alphabet,
+            lm_alpha,
+            lm_beta,
+            word_delimiter: delimiter_label,
+            hotwords: hotwords.as_deref(),
+            hotword_weight,
+            lexicon: lexicon.as_ref(),
+            unknown_word_penalty,
+        };
 
         let probs = unsafe { probs.as_array() };
+        let probs = to_log_probs(probs, log_probs);
+
+        if let Some(pronunciations) = pronunciations {
+            if lm_model.is_some() || config.hotwords.is_some() || config.lexicon.is_some() {
+                return Err(PyValueError::new_err(
+                    "Pronunciations can't be combined with a language model, hotwords or a lexicon",
+                ));
+            }
+            let pronunciations = get_pronunciations(&alphabet, blank, pronunciations)?;
+            let mut scorer = PronunciationScorer::new(&pronunciations, delimiter_label);
+            let probs = probs.into_owned();
+            let results = py.allow_threads(|| {
+                pronunciation::beam_search(
+                    &probs,
+                    &alphabet,
+                    blank,
+                    beam_size,
+                    beam_cut_threshold,
+                    score_cut,
+                    envelope.as_deref(),
+                    &mut scorer,
+                )
+            })?;
+            // the words are the output, so there is no word boundary marker to strip
+            return Ok(to_py_hypotheses(results, false, frame_stride));
+        }
 
-        // alphabet size minus the blank label
-        let alphabet_size = alphabet.len();
-        if probs.shape()[1] != alphabet_size {
+        let search = Search {
+            probs,
+            alphabet: &alphabet,
+            blank,
+            beam_size,
+            beam_cut_threshold,
+            score_cut,
+            envelope: envelope.as_deref(),
+        };
+        let results = run_with_lm(py, lm_model, config, search)?;
+
+        Ok(to_py_hypotheses(results, sentencepiece, frame_stride))
+    }
+
+    #[pyfn(_m)]
+    #[pyo3(name = "beam_search_batch")]
+    #[allow(clippy::too_many_arguments)]
+    fn beam_search_batch<'py>(
+        py: Python<'py>,
+        probs: &PyArray3<f32>,
+        lengths: Vec<usize>,
+        alphabet: &PyAny,
+        beam_size: usize,
+        lm_model: Option<&PyAny>,
+        lm_alpha: f32,
+        lm_beta: f32,
+        log_probs: bool,
+        beam_cut_threshold: f32,
+        score_cut: f32,
+        frame_stride: Option<f64>,
+        sentencepiece: bool,
+        blank_index: BlankIndex,
+        word_delimiter: Option<&str>,
+        hotwords: Option<Vec<String>>,
+        hotword_weight: f32,
+        lexicon: Option<Vec<String>>,
+        unknown_word_penalty: Option<f32>,
+    ) -> PyResult<Vec<Vec<PyHypothesis>>> {
+        let alphabet = get_alphabet(alphabet, sentencepiece)?;
+        check_alphabet(probs.shape()[2], &alphabet)?;
+        check_cuts(beam_cut_threshold, score_cut)?;
+        let blank = get_blank(&blank_index, alphabet.len())?;
+        let delimiter_label = get_word_delimiter(&alphabet, word_delimiter)?;
+        let lexicon = lexicon.map(Lexicon::new);
+        let config = ScorerConfig {
+            alphabet: &alphabet,
+            lm_alpha,
+            lm_beta,
+            word_delimiter: delimiter_label,
+            hotwords: hotwords.as_deref(),
+            hotword_weight,
+            lexicon: lexicon.as_ref(),
+            unknown_word_penalty,
+        };
+        if lengths.len() != probs.shape()[0] {
+            return Err(PyAssertionError::new_err(format!(
+                "Expected len(lengths) ({}) == batch size ({})",
+                lengths.len(),
+                probs.shape()[0]
+            )));
+        }
+        if let Some(&length) = lengths.iter().find(|&&x| x > probs.shape()[1]) {
             return Err(PyAssertionError::new_err(format!(
-                "Expected props.shape[1] ({}) == alphabet size ({})",
-                probs.shape()[1],
-                alphabet_size
+                "Length {} is longer than the input ({})",
+                length,
+                probs.shape()[1]
             )));
         }
 
-        let mut suffix_tree = SuffixTree::new(alphabet_size);
-        let mut beam = vec![SearchPoint {
-            node: ROOT_NODE,
-            prob: 1.0,
-        }];
-        let mut next_beam = Vec::new();
-
-        for (idx, pr) in probs.outer_iter().enumerate() {
-            next_beam.clear();
-
-            for &SearchPoint { node, prob } in beam.iter() {
-                let tip_label = suffix_tree.label(node);
-
-                let mut curr_path = suffix_tree.get_path(node, alphabet);
-
-                next_beam.push(SearchPoint {
-                    node,
-                    prob: prob * pr[0] + get_lm_prob(&curr_path, idx, lm_model, lm_alpha, lm_beta)?,
-                });
-
-                for (label, pr_b) in pr.iter().skip(1).enumerate() {
-                    if Some(label) == tip_label {
-                        next_beam.push(SearchPoint {
-                            node,
-                            prob: prob * pr_b
-                                + get_lm_prob(&curr_path, idx, lm_model, lm_alpha, lm_beta)?,
-                        });
-                    } else {
-                        curr_path.push(alphabet.as_bytes()[label] as char);
-                        let new_node_idx = suffix_tree
-                            .get_child(node, label)
-                            .unwrap_or_else(|| suffix_tree.add_node(node, label, idx));
-
-                        next_beam.push(SearchPoint {
-                            node: new_node_idx,
-                            prob: prob * pr_b
-                                + get_lm_prob(&curr_path, idx, lm_model, lm_alpha, lm_beta)?,
-                        });
-
-                        curr_path.pop();
-                    }
-                }
-            }
-            std::mem::swap(&mut beam, &mut next_beam);
-
-            const DELETE_MARKER: i32 = i32::MIN;
-            beam.sort_by_key(|x| x.node);
-            let mut last_key = DELETE_MARKER;
-            let mut last_key_pos = 0;
-            for i in 0..beam.len() {
-                let beam_item = beam[i];
-                if beam_item.node == last_key {
-                    beam[last_key_pos].prob += beam_item.prob;
-                    beam[i].node = DELETE_MARKER;
-                } else {
-                    last_key_pos = i;
-                    last_key = beam_item.node;
-                }
-            }
+        let probs = unsafe { probs.as_array() };
 
-            beam.retain(|x| x.node != DELETE_MARKER);
-            let mut has_nans = false;
-            beam.sort_unstable_by(|a, b| {
-                (b.prob).partial_cmp(&(a.prob)).unwrap_or_else(|| {
-                    has_nans = true;
-                    std::cmp::Ordering::Equal // don't really care
-                })
-            });
-            if has_nans {
-                return Err(PyRuntimeError::new_err(format!(
-                    "{}",
-                    SearchError::IncomparableValues
-                )));
-            }
-            beam.truncate(beam_size);
-            if beam.is_empty() {
-                // we've run out of beam (probably the threshold is too high)
-                return Err(PyRuntimeError::new_err(format!(
-                    "{}",
-                    SearchError::RanOutOfBeam
-                )));
-            }
-            let top = beam[0].prob;
-            for mut x in &mut beam {
-                x.prob /= top;
-            }
+        let search = BatchSearch {
+            probs,
+            lengths: &lengths,
+            log_probs,
+            alphabet: &alphabet,
+            blank,
+            beam_size,
+            beam_cut_threshold,
+            score_cut,
+        };
+        let results = run_with_lm(py, lm_model, config, search)?;
+
+        Ok(results
+            .into_iter()
+            .map(|item| to_py_hypotheses(item, sentencepiece, frame_stride))
+            .collect())
+    }
+
+    #[pyfn(_m)]
+    #[pyo3(name = "greedy_search")]
+    fn greedy_search<'py>(
+        _py: Python<'py>,
+        probs: &PyArray2<f32>,
+        alphabet: &PyAny,
+        log_probs: bool,
+        sentencepiece: bool,
+        blank_index: BlankIndex,
+    ) -> PyResult<(String, Vec<usize>, Vec<f32>)> {
+        let alphabet = get_alphabet(alphabet, sentencepiece)?;
+        check_alphabet(probs.shape()[1], &alphabet)?;
+        let blank = get_blank(&blank_index, alphabet.len())?;
+
+        let probs = unsafe { probs.as_array() };
+        let probs = to_log_probs(probs, log_probs);
+
+        let (path, frames, label_probs) = search::greedy_search(&probs, &alphabet, blank)?;
+        if sentencepiece {
+            Ok((path.trim_start_matches(' ').to_owned(), frames, label_probs))
+        } else {
+            Ok((path, frames, label_probs))
         }
+    }
 
-        let mut ans = Vec::new();
+    #[pyfn(_m)]
+    #[pyo3(name = "forced_align")]
+    fn forced_align<'py>(
+        _py: Python<'py>,
+        probs: &PyArray2<f32>,
+        alphabet: &PyAny,
+        target: &PyString,
+        log_probs: bool,
+        blank_index: BlankIndex,
+    ) -> PyResult<(f32, Vec<Span>)> {
+        let alphabet = get_alphabet(alphabet, false)?;
+        check_alphabet(probs.shape()[1], &alphabet)?;
+        let blank = get_blank(&blank_index, alphabet.len())?;
+        let target = encode_target(&alphabet, blank, target.to_str()?)?;
 
-        beam.drain(..).for_each(|beam| {
-            if beam.node != ROOT_NODE {
-                ans.push((suffix_tree.get_path(beam.node, alphabet), beam.prob));
-            }
-        });
+        let probs = unsafe { probs.as_array() };
+        let probs = to_log_probs(probs, log_probs);
+
+        let (total, spans) = align::viterbi_align(&probs, &target, blank)?;
+        Ok((
+            total,
+            spans.iter().map(|x| (x.start, x.end, x.score)).collect(),
+        ))
+    }
+
+    #[pyfn(_m)]
+    #[pyo3(name = "ctc_log_likelihood")]
+    fn ctc_log_likelihood<'py>(
+        py: Python<'py>,
+        probs: &PyArray2<f32>,
+        alphabet: &PyAny,
+        target: &PyString,
+        log_probs: bool,
+        posteriors: bool,
+        blank_index: BlankIndex,
+    ) -> PyResult<(f32, Option<&'py PyArray2<f32>>)> {
+        let alphabet = get_alphabet(alphabet, false)?;
+        check_alphabet(probs.shape()[1], &alphabet)?;
+        let blank = get_blank(&blank_index, alphabet.len())?;
+        let target = encode_target(&alphabet, blank, target.to_str()?)?;
+
+        let probs = unsafe { probs.as_array() };
+        let probs = to_log_probs(probs, log_probs);
 
-        Ok(ans)
+        let (total, occupancy) = align::forward_backward(&probs, &target, blank, posteriors)?;
+        Ok((total, occupancy.map(|x| x.into_pyarray(py))))
     }
 
+    #[pyfn(_m)]
+    #[pyo3(name = "convert_arpa")]
+    fn convert_arpa<'py>(py: Python<'py>, arpa_path: &str, binary_path: &str) -> PyResult<()> {
+        py.allow_threads(|| {
+            let arpa = std::io::BufReader::new(std::fs::File::open(arpa_path)?);
+            let binary = std::io::BufWriter::new(std::fs::File::create(binary_path)?);
+            ngram::convert_arpa(arpa, binary)
+        })?;
+        Ok(())
+    }
+
+    _m.add_class::<StreamingDecoder>()?;
+    _m.add_class::<NgramLanguageModel>()?;
+
     Ok(())
 }
@@ -0,0 +1,881 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::{BufRead, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use memmap2::Mmap;
+
+/// The log probability KenLM gives `<unk>` when the model doesn't contain it (in log10).
+const DEFAULT_UNK_LOG10_PROB: f32 = -100.0;
+
+/// The start of every binary model.
+const MAGIC: &[u8; 8] = b"CTCNGRM1";
+
+/// How many values probabilities and backoffs are quantized to in binary models.
+const QUANTIZATION_LEVELS: usize = 256;
+
+/// Marks a special word that is missing from a binary model.
+const NO_WORD: u32 = u32::MAX;
+
+#[derive(Debug)]
+pub enum LmError {
+    Io(std::io::Error),
+    Format { line: usize, message: String },
+    Binary(String),
+}
+
+impl std::fmt::Display for LmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LmError::Io(err) => write!(f, "Can't read language model: {}", err),
+            LmError::Format { line, message } => {
+                write!(f, "Invalid ARPA file (line {}): {}", line, message)
+            }
+            LmError::Binary(message) => write!(f, "Invalid binary language model: {}", message),
+        }
+    }
+}
+
+impl From<std::io::Error> for LmError {
+    fn from(err: std::io::Error) -> Self {
+        LmError::Io(err)
+    }
+}
+
+/// What the n-gram model's words are.
+#[derive(Clone, Debug)]
+pub enum Unit {
+    /// Every character of the text is a word, with whitespace looked up as `space`.
+    Characters { space: String },
+    /// The text is split into words at whitespace.
+    Words,
+}
+
+/// The n-grams of one order, sorted by their word ids.
+struct Order {
+    /// The word ids of every n-gram, `n` per n-gram.
+    keys: Vec<u32>,
+    /// Log probabilities (natural log).
+    probs: Vec<f32>,
+    /// Log backoff weights (natural log).
+    backoffs: Vec<f32>,
+}
+
+impl Order {
+    fn find(&self, ngram: &[u32]) -> Option<usize> {
+        let n = ngram.len();
+        let (mut lo, mut hi) = (0, self.probs.len());
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            match self.keys[mid * n..(mid + 1) * n].cmp(ngram) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return Some(mid),
+            }
+        }
+        None
+    }
+}
+
+/// N-grams read from an ARPA file.
+struct Arpa {
+    vocab: HashMap<String, u32>,
+    /// `orders[n - 1]` holds the n-grams.
+    orders: Vec<Order>,
+    unk: u32,
+    bos: Option<u32>,
+    eos: Option<u32>,
+}
+
+impl Arpa {
+    fn read<R: BufRead>(reader: R) -> Result<Self, LmError> {
+        let mut vocab = HashMap::new();
+        let mut counts: Vec<usize> = Vec::new();
+        let mut entries: Vec<Vec<(Vec<u32>, f32, f32)>> = Vec::new();
+        // None before \data\, 0 in it and then the order of the n-grams being read
+        let mut section = None;
+
+        for (line_number, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            let error = |message: &str| LmError::Format {
+                line: line_number + 1,
+                message: message.to_owned(),
+            };
+            if line.is_empty() {
+                continue;
+            }
+            if line == "\\data\\" {
+                section = Some(0);
+                continue;
+            }
+            if line == "\\end\\" {
+                break;
+            }
+            if let Some(order) = line
+                .strip_prefix('\\')
+                .and_then(|x| x.strip_suffix("-grams:"))
+            {
+                let order: usize = order.parse().map_err(|_| error("bad section header"))?;
+                if order != entries.len() + 1 || order > counts.len() {
+                    return Err(error("unexpected n-gram section"));
+                }
+                // the count in the header may be wrong, so it isn't trusted with an allocation
+                entries.push(Vec::new());
+                section = Some(order);
+                continue;
+            }
+
+            match section {
+                None => continue,
+                Some(0) => {
+                    let count = line
+                        .strip_prefix("ngram ")
+                        .and_then(|x| x.split_once('='))
+                        .and_then(|(order, count)| {
+                            Some((
+                                order.trim().parse::<usize>().ok()?,
+                                count.trim().parse::<usize>().ok()?,
+                            ))
+                        });
+                    match count {
+                        Some((order, count)) if order == counts.len() + 1 => counts.push(count),
+                        _ => return Err(error("bad n-gram count")),
+                    }
+                }
+                Some(order) => {
+                    let fields: Vec<&str> = line.split_whitespace().collect();
+                    if fields.len() != order + 1 && fields.len() != order + 2 {
+                        return Err(error("wrong number of fields"));
+                    }
+                    let prob: f32 = fields[0].parse().map_err(|_| error("bad probability"))?;
+                    let backoff: f32 = match fields.get(order + 1) {
+                        Some(x) => x.parse().map_err(|_| error("bad backoff"))?,
+                        None => 0.0,
+                    };
+                    let mut key = Vec::with_capacity(order);
+                    for &word in &fields[1..=order] {
+                        let id = if order == 1 {
+                            let next_id = vocab.len() as u32;
+                            *vocab.entry(word.to_owned()).or_insert(next_id)
+                        } else {
+                            *vocab
+                                .get(word)
+                                .ok_or_else(|| error("word is missing from the unigrams"))?
+                        };
+                        key.push(id);
+                    }
+                    entries[order - 1].push((key, prob, backoff));
+                }
+            }
+        }
+        if entries.is_empty() {
+            return Err(LmError::Format {
+                line: 0,
+                message: "no n-grams".to_owned(),
+            });
+        }
+
+        let unk = match vocab.get("<unk>") {
+            Some(&unk) => unk,
+            None => {
+                let unk = vocab.len() as u32;
+                vocab.insert("<unk>".to_owned(), unk);
+                entries[0].push((vec![unk], DEFAULT_UNK_LOG10_PROB, 0.0));
+                unk
+            }
+        };
+
+        let orders = entries
+            .into_iter()
+            .map(|mut ngrams| {
+                ngrams.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+                Order {
+                    keys: ngrams.iter().flat_map(|x| x.0.iter().copied()).collect(),
+                    probs: ngrams
+                        .iter()
+                        .map(|x| x.1 * std::f32::consts::LN_10)
+                        .collect(),
+                    backoffs: ngrams
+                        .iter()
+                        .map(|x| x.2 * std::f32::consts::LN_10)
+                        .collect(),
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            bos: vocab.get("<s>").copied(),
+            eos: vocab.get("</s>").copied(),
+            vocab,
+            orders,
+            unk,
+        })
+    }
+
+    fn lookup(&self, ngram: &[u32]) -> Option<(f32, f32)> {
+        let order = &self.orders[ngram.len() - 1];
+        order
+            .find(ngram)
+            .map(|i| (order.probs[i], order.backoffs[i]))
+    }
+
+    /// The word of every id.
+    fn words_by_id(&self) -> Vec<&str> {
+        let mut words = vec![""; self.vocab.len()];
+        for (word, &id) in &self.vocab {
+            words[id as usize] = word;
+        }
+        words
+    }
+}
+
+/// Reads a little-endian value. The sections of a binary model and the offsets inside them are
+/// checked when it is opened, so this doesn't fail on the offsets it reads.
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn f32_at(bytes: &[u8], offset: usize) -> f32 {
+    f32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Rounds `len` up to a multiple of 4, which keeps all sections of binary models aligned.
+fn padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Picks the values a quantized array can hold: the means of equally sized bins of the sorted
+/// values, in ascending order.
+fn codebook(values: &[f32]) -> Vec<f32> {
+    let mut sorted = values.to_vec();
+    sorted.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let mut centers = Vec::with_capacity(QUANTIZATION_LEVELS);
+    for bin in 0..QUANTIZATION_LEVELS {
+        let bin = &sorted[bin * sorted.len() / QUANTIZATION_LEVELS
+            ..(bin + 1) * sorted.len() / QUANTIZATION_LEVELS];
+        if !bin.is_empty() {
+            let sum: f64 = bin.iter().map(|&x| x as f64).sum();
+            centers.push((sum / bin.len() as f64) as f32);
+        }
+    }
+    let last = centers.last().copied().unwrap_or(0.0);
+    centers.resize(QUANTIZATION_LEVELS, last);
+    centers
+}
+
+/// The index of the value in `codebook` that is closest to `value`.
+fn quantize(codebook: &[f32], value: f32) -> u8 {
+    let above = codebook
+        .partition_point(|&x| x < value)
+        .min(codebook.len() - 1);
+    if above > 0 && value - codebook[above - 1] < codebook[above] - value {
+        (above - 1) as u8
+    } else {
+        above as u8
+    }
+}
+
+fn write_u32s<W: Write>(out: &mut W, values: impl IntoIterator<Item = u32>) -> std::io::Result<()> {
+    for value in values {
+        out.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn write_padded<W: Write>(out: &mut W, bytes: &[u8]) -> std::io::Result<()> {
+    out.write_all(bytes)?;
+    out.write_all(&[0; 3][..padded(bytes.len()) - bytes.len()])
+}
+
+/// Converts a model from ARPA format into the binary format that [`NgramModel::open`] maps into
+/// memory.
+///
+/// The binary format is little-endian with every section padded to 4 bytes:
+///
+/// * the magic bytes `CTCNGRM1`, then the order, the vocabulary size and the ids of `<unk>`, `<s>`
+///   and `</s>` (`u32::MAX` if missing), and the number of n-grams of every order (all `u32`)
+/// * the vocabulary: byte offsets of every word into the word data (`vocab_size + 1` of them), the
+///   ids sorted by word and the UTF-8 word data
+/// * for every order n: a codebook of 256 `f32` log probabilities and, for n below the order, 256
+///   backoffs; for n > 1 the last word of every n-gram; for n below the order the index of the
+///   first (n + 1)-gram extending every n-gram (plus the total at the end); and finally the
+///   probability codes and, for n below the order, the backoff codes (one byte each)
+///
+/// N-grams are sorted, so the unigram of word id i is at index i and the extensions of an n-gram
+/// form a range sorted by word id, i.e. the n-grams form a trie.
+pub fn convert_arpa<R: BufRead, W: Write>(arpa: R, mut out: W) -> Result<(), LmError> {
+    let arpa = Arpa::read(arpa)?;
+    let error = |message: &str| LmError::Binary(message.to_owned());
+    let order = arpa.orders.len();
+    let words = arpa.words_by_id();
+    if arpa.orders[0].probs.len() != words.len() {
+        return Err(error("the ARPA file has duplicate unigrams"));
+    }
+
+    out.write_all(MAGIC)?;
+    write_u32s(
+        &mut out,
+        [
+            order as u32,
+            words.len() as u32,
+            arpa.unk,
+            arpa.bos.unwrap_or(NO_WORD),
+            arpa.eos.unwrap_or(NO_WORD),
+        ]
+        .iter()
+        .copied(),
+    )?;
+    write_u32s(&mut out, arpa.orders.iter().map(|x| x.probs.len() as u32))?;
+
+    let mut offset = 0;
+    let mut offsets = vec![0];
+    for word in &words {
+        offset += word.len() as u32;
+        offsets.push(offset);
+    }
+    write_u32s(&mut out, offsets)?;
+    let mut sorted: Vec<u32> = (0..words.len() as u32).collect();
+    sorted.sort_unstable_by_key(|&id| words[id as usize]);
+    write_u32s(&mut out, sorted)?;
+    write_padded(&mut out, words.concat().as_bytes())?;
+
+    for (n, ngrams) in arpa.orders.iter().enumerate() {
+        let highest = n + 1 == order;
+        let probs = codebook(&ngrams.probs);
+        let backoffs = if highest {
+            Vec::new()
+        } else {
+            codebook(&ngrams.backoffs)
+        };
+        write_u32s(&mut out, probs.iter().map(|x| x.to_bits()))?;
+        if !highest {
+            write_u32s(&mut out, backoffs.iter().map(|x| x.to_bits()))?;
+        }
+        if n > 0 {
+            write_u32s(&mut out, ngrams.keys.iter().skip(n).step_by(n + 1).copied())?;
+        }
+        if !highest {
+            let next = &arpa.orders[n + 1];
+            let mut children = vec![0; ngrams.probs.len() + 1];
+            for key in next.keys.chunks(n + 2) {
+                let parent = ngrams
+                    .find(&key[..n + 1])
+                    .ok_or_else(|| error("the ARPA file has an n-gram without its prefix"))?;
+                children[parent + 1] += 1;
+            }
+            for i in 1..children.len() {
+                children[i] += children[i - 1];
+            }
+            write_u32s(&mut out, children)?;
+        }
+        let mut codes: Vec<u8> = ngrams.probs.iter().map(|&x| quantize(&probs, x)).collect();
+        if !highest {
+            codes.extend(ngrams.backoffs.iter().map(|&x| quantize(&backoffs, x)));
+        }
+        write_padded(&mut out, &codes)?;
+    }
+
+    out.flush()?;
+    Ok(())
+}
+
+/// Where the arrays of one order are in a binary model.
+struct Level {
+    count: usize,
+    prob_codebook: usize,
+    backoff_codebook: Option<usize>,
+    words: usize,
+    children: Option<usize>,
+    prob_codes: usize,
+    backoff_codes: Option<usize>,
+}
+
+enum Bytes {
+    Mapped(Mmap),
+    #[cfg(test)]
+    Owned(Vec<u8>),
+}
+
+impl std::ops::Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Bytes::Mapped(x) => x,
+            #[cfg(test)]
+            Bytes::Owned(x) => x,
+        }
+    }
+}
+
+/// N-grams in the binary format written by [`convert_arpa`], read in place.
+struct Binary {
+    bytes: Bytes,
+    vocab_size: usize,
+    unk: u32,
+    bos: Option<u32>,
+    eos: Option<u32>,
+    vocab_offsets: usize,
+    vocab_sorted: usize,
+    vocab_data: usize,
+    levels: Vec<Level>,
+}
+
+impl Binary {
+    fn new(bytes: Bytes) -> Result<Self, LmError> {
+        let error = |message: &str| LmError::Binary(message.to_owned());
+        let truncated = || error("the file is truncated");
+        if !bytes.starts_with(MAGIC) {
+            return Err(error(
+                "the file doesn't start with the expected magic bytes",
+            ));
+        }
+        if bytes.len() < MAGIC.len() + 5 * 4 {
+            return Err(truncated());
+        }
+        let header = |i: usize| u32_at(&bytes, MAGIC.len() + 4 * i);
+        let (order, vocab_size) = (header(0) as usize, header(1) as usize);
+        let special = |id: u32| if id == NO_WORD { None } else { Some(id) };
+        let (unk, bos, eos) = (header(2), special(header(3)), special(header(4)));
+        let mut offset = MAGIC.len() + 4 * (5 + order);
+        if order == 0 || bytes.len() < offset {
+            return Err(truncated());
+        }
+        let counts: Vec<usize> = (0..order).map(|i| header(5 + i) as usize).collect();
+        if counts[0] != vocab_size || unk as usize >= vocab_size {
+            return Err(error("the vocabulary doesn't match the unigrams"));
+        }
+
+        let vocab_offsets = offset;
+        let vocab_sorted = vocab_offsets + 4 * (vocab_size + 1);
+        let vocab_data = vocab_sorted + 4 * vocab_size;
+        if bytes.len() < vocab_data {
+            return Err(truncated());
+        }
+        offset = vocab_data + padded(u32_at(&bytes, vocab_sorted - 4) as usize);
+
+        let mut levels = Vec::with_capacity(order);
+        for (n, &count) in counts.iter().enumerate() {
+            let highest = n + 1 == order;
+            let prob_codebook = offset;
+            offset += 4 * QUANTIZATION_LEVELS;
+            let backoff_codebook = if highest {
+                None
+            } else {
+                offset += 4 * QUANTIZATION_LEVELS;
+                Some(offset - 4 * QUANTIZATION_LEVELS)
+            };
+            let words = offset;
+            if n > 0 {
+                offset += 4 * count;
+            }
+            let children = if highest {
+                None
+            } else {
+                offset += 4 * (count + 1);
+                Some(offset - 4 * (count + 1))
+            };
+            let prob_codes = offset;
+            let backoff_codes = if highest { None } else { Some(offset + count) };
+            offset += padded(if highest { count } else { 2 * count });
+            levels.push(Level {
+                count,
+                prob_codebook,
+                backoff_codebook,
+                words,
+                children,
+                prob_codes,
+                backoff_codes,
+            });
+        }
+        if bytes.len() != offset {
+            return Err(error("the file size doesn't match its header"));
+        }
+
+        // everything decoding follows must stay within the file
+        let is_word = |id: u32| (id as usize) < vocab_size;
+        if !bos.into_iter().chain(eos).all(is_word) {
+            return Err(error("the sentence markers are out of the vocabulary"));
+        }
+        let vocab_offsets_in_order = (0..vocab_size).all(|i| {
+            u32_at(&bytes, vocab_offsets + 4 * i) <= u32_at(&bytes, vocab_offsets + 4 * i + 4)
+        });
+        if u32_at(&bytes, vocab_offsets) != 0 || !vocab_offsets_in_order {
+            return Err(error("the vocabulary offsets are out of order"));
+        }
+        if !(0..vocab_size).all(|i| is_word(u32_at(&bytes, vocab_sorted + 4 * i))) {
+            return Err(error("the sorted vocabulary has bad word ids"));
+        }
+        for (n, level) in levels.iter().enumerate() {
+            if n > 0 && !(0..level.count).all(|i| is_word(u32_at(&bytes, level.words + 4 * i))) {
+                return Err(error("an n-gram has a bad word id"));
+            }
+            if let Some(children) = level.children {
+                let child = |i: usize| u32_at(&bytes, children + 4 * i) as usize;
+                let in_order = (0..level.count).all(|i| child(i) <= child(i + 1));
+                if child(0) != 0 || !in_order || child(level.count) != levels[n + 1].count {
+                    return Err(error("the n-gram extensions are out of range"));
+                }
+            }
+        }
+
+        Ok(Self {
+            bytes,
+            vocab_size,
+            unk,
+            bos,
+            eos,
+            vocab_offsets,
+            vocab_sorted,
+            vocab_data,
+            levels,
+        })
+    }
+
+    fn word(&self, id: u32) -> &[u8] {
+        let start = u32_at(&self.bytes, self.vocab_offsets + 4 * id as usize) as usize;
+        let end = u32_at(&self.bytes, self.vocab_offsets + 4 * id as usize + 4) as usize;
+        &self.bytes[self.vocab_data + start..self.vocab_data + end]
+    }
+
+    fn word_id(&self, word: &str) -> Option<u32> {
+        let (mut lo, mut hi) = (0, self.vocab_size);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            let id = u32_at(&self.bytes, self.vocab_sorted + 4 * mid);
+            match self.word(id).cmp(word.as_bytes()) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return Some(id),
+            }
+        }
+        None
+    }
+
+    fn lookup(&self, ngram: &[u32]) -> Option<(f32, f32)> {
+        let mut index = ngram[0] as usize;
+        if index >= self.levels[0].count {
+            return None;
+        }
+        for (n, &word) in ngram.iter().enumerate().skip(1) {
+            let children = self.levels[n - 1].children?;
+            let mut lo = u32_at(&self.bytes, children + 4 * index) as usize;
+            let mut hi = u32_at(&self.bytes, children + 4 * index + 4) as usize;
+            let words = self.levels[n].words;
+            index = loop {
+                if lo >= hi {
+                    return None;
+                }
+                let mid = (lo + hi) / 2;
+                match u32_at(&self.bytes, words + 4 * mid).cmp(&word) {
+                    std::cmp::Ordering::Less => lo = mid + 1,
+                    std::cmp::Ordering::Greater => hi = mid,
+                    std::cmp::Ordering::Equal => break mid,
+                }
+            };
+        }
+
+        let level = &self.levels[ngram.len() - 1];
+        let code = self.bytes[level.prob_codes + index] as usize;
+        let prob = f32_at(&self.bytes, level.prob_codebook + 4 * code);
+        let backoff = match (level.backoff_codebook, level.backoff_codes) {
+            (Some(codebook), Some(codes)) => {
+                let code = self.bytes[codes + index] as usize;
+                f32_at(&self.bytes, codebook + 4 * code)
+            }
+            _ => 0.0,
+        };
+        Some((prob, backoff))
+    }
+}
+
+enum Storage {
+    Arpa(Arpa),
+    Binary(Binary),
+}
+
+/// A backoff n-gram language model, loaded from an ARPA file or a binary one written by
+/// [`convert_arpa`].
+pub struct NgramModel {
+    unit: Unit,
+    storage: Storage,
+}
+
+impl NgramModel {
+    /// Reads a model in ARPA format.
+    pub fn from_arpa<R: BufRead>(reader: R, unit: Unit) -> Result<Self, LmError> {
+        Ok(Self {
+            unit,
+            storage: Storage::Arpa(Arpa::read(reader)?),
+        })
+    }
+
+    /// Reads a model in the binary format from memory.
+    #[cfg(test)]
+    pub fn from_binary(bytes: Vec<u8>, unit: Unit) -> Result<Self, LmError> {
+        Ok(Self {
+            unit,
+            storage: Storage::Binary(Binary::new(Bytes::Owned(bytes))?),
+        })
+    }
+
+    /// Loads the model at `path`, mapping binary models into memory (so that processes using the
+    /// same file share it) and reading anything else as ARPA.
+    pub fn open<P: AsRef<Path>>(path: P, unit: Unit) -> Result<Self, LmError> {
+        let mut file = std::fs::File::open(path)?;
+        let mut magic = [0; 8];
+        let is_binary = file.read_exact(&mut magic).is_ok() && &magic == MAGIC;
+        file.seek(SeekFrom::Start(0))?;
+        if !is_binary {
+            return Self::from_arpa(std::io::BufReader::new(file), unit);
+        }
+        // the model is only ever read, and like any other data file it must not be changed while
+        // in use
+        let bytes = unsafe { Mmap::map(&file)? };
+        Ok(Self {
+            unit,
+            storage: Storage::Binary(Binary::new(Bytes::Mapped(bytes))?),
+        })
+    }
+
+    /// What the model's words are.
+    pub fn unit(&self) -> &Unit {
+        &self.unit
+    }
+
+    /// The length of the longest n-grams.
+    pub fn order(&self) -> usize {
+        match &self.storage {
+            Storage::Arpa(arpa) => arpa.orders.len(),
+            Storage::Binary(binary) => binary.levels.len(),
+        }
+    }
+
+    /// The id of `word`, which is the id of `<unk>` for unknown words.
+    pub fn word_id(&self, word: &str) -> u32 {
+        match &self.storage {
+            Storage::Arpa(arpa) => arpa.vocab.get(word).copied().unwrap_or(arpa.unk),
+            Storage::Binary(binary) => binary.word_id(word).unwrap_or(binary.unk),
+        }
+    }
+
+    fn lookup(&self, ngram: &[u32]) -> Option<(f32, f32)> {
+        match &self.storage {
+            Storage::Arpa(arpa) => arpa.lookup(ngram),
+            Storage::Binary(binary) => binary.lookup(ngram),
+        }
+    }
+
+    /// The log probability (natural log) of `word` following `context`, backing off to shorter
+    /// contexts as needed. Only the last `order() - 1` words of the context are used.
+    pub fn log_prob(&self, context: &[u32], word: u32) -> f32 {
+        let context = &context[context.len().saturating_sub(self.order() - 1)..];
+        let mut ngram = Vec::with_capacity(context.len() + 1);
+        let mut backoff = 0.0;
+        for start in 0..=context.len() {
+            let history = &context[start..];
+            ngram.clear();
+            ngram.extend_from_slice(history);
+            ngram.push(word);
+            if let Some((prob, _)) = self.lookup(&ngram) {
+                return backoff + prob;
+            }
+            if !history.is_empty() {
+                if let Some((_, weight)) = self.lookup(history) {
+                    backoff += weight;
+                }
+            }
+        }
+        // every word has a unigram (unknown ones are `<unk>`), so this isn't reached
+        backoff + DEFAULT_UNK_LOG10_PROB * std::f32::consts::LN_10
+    }
+
+    /// The ids of the words of `text`, according to the model's unit.
+    pub fn words(&self, text: &str) -> Vec<u32> {
+        match &self.unit {
+            Unit::Characters { space } => {
+                let mut buf = [0; 4];
+                text.chars()
+                    .map(|c| {
+                        if c.is_whitespace() {
+                            self.word_id(space)
+                        } else {
+                            self.word_id(c.encode_utf8(&mut buf))
+                        }
+                    })
+                    .collect()
+            }
+            Unit::Words => text.split_whitespace().map(|x| self.word_id(x)).collect(),
+        }
+    }
+
+    /// The ids of `<s>` and `</s>`, if the model has them.
+    fn sentence_markers(&self) -> (Option<u32>, Option<u32>) {
+        match &self.storage {
+            Storage::Arpa(arpa) => (arpa.bos, arpa.eos),
+            Storage::Binary(binary) => (binary.bos, binary.eos),
+        }
+    }
+
+    /// The log probability (natural log) of `text` at the start of a sentence, including the end of
+    /// the sentence if `eos` is set.
+    pub fn score(&self, text: &str, eos: bool) -> f32 {
+        let mut context = self.start();
+        let mut total = 0.0;
+        for word in self.words(text) {
+            total += self.advance(&mut context, word);
+        }
+        if eos {
+            total += self.end_of_sentence(&context);
+        }
+        total
+    }
+
+    /// The log probability (natural log) of the sentence ending after `context`, or 0 if the model
+    /// has no end of sentence marker.
+    pub fn end_of_sentence(&self, context: &[u32]) -> f32 {
+        match self.sentence_markers().1 {
+            Some(end) => self.log_prob(context, end),
+            None => 0.0,
+        }
+    }
+
+    /// The context of the first word of a sentence.
+    pub fn start(&self) -> Vec<u32> {
+        self.sentence_markers().0.into_iter().collect()
+    }
+
+    /// The log probability (natural log) of `word` following `context`, which the word is then
+    /// appended to. Words too far back to matter are dropped from the context.
+    pub fn advance(&self, context: &mut Vec<u32>, word: u32) -> f32 {
+        let prob = self.log_prob(context, word);
+        context.push(word);
+        let excess = context.len().saturating_sub(self.order() - 1);
+        context.drain(..excess);
+        prob
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A small word bigram model, shared with the scorer tests.
+    pub(crate) const ARPA: &str = "
+\\data\\
+ngram 1=5
+ngram 2=3
+
+\\1-grams:
+-1.0\t<s>\t-0.5
+-0.5\ta\t-0.25
+-0.7\tb\t-0.1
+-0.9\t</s>
+-2.0\t<unk>
+
+\\2-grams:
+-0.2\t<s> a
+-0.3\ta b
+-0.4\tb </s>
+
+\\end\\
+";
+
+    fn model(unit: Unit) -> NgramModel {
+        NgramModel::from_arpa(ARPA.as_bytes(), unit).unwrap()
+    }
+
+    #[test]
+    fn test_backoff() {
+        let lm = model(Unit::Words);
+        let (a, b) = (lm.word_id("a"), lm.word_id("b"));
+        let ln = |x: f32| x * std::f32::consts::LN_10;
+        assert!((lm.log_prob(&[a], b) - ln(-0.3)).abs() < 1e-6);
+        // "b a" isn't in the model, so back off through b's weight to the unigram
+        assert!((lm.log_prob(&[b], a) - ln(-0.1 - 0.5)).abs() < 1e-6);
+        assert!((lm.log_prob(&[a, b], a) - ln(-0.1 - 0.5)).abs() < 1e-6);
+        assert_eq!(lm.word_id("c"), lm.word_id("<unk>"));
+        assert!((lm.log_prob(&[], lm.word_id("c")) - ln(-2.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_score() {
+        let ln = |x: f32| x * std::f32::consts::LN_10;
+        let words = model(Unit::Words);
+        assert!((words.score("a b", false) - ln(-0.2 - 0.3)).abs() < 1e-6);
+        assert!((words.score("a b", true) - ln(-0.2 - 0.3 - 0.4)).abs() < 1e-6);
+        let mut context = words.start();
+        words.advance(&mut context, words.word_id("a"));
+        assert!((words.advance(&mut context, words.word_id("b")) - ln(-0.3)).abs() < 1e-6);
+        assert_eq!(context, vec![words.word_id("b")]);
+
+        let chars = model(Unit::Characters {
+            space: "<unk>".to_owned(),
+        });
+        assert!((chars.score("ab", false) - ln(-0.2 - 0.3)).abs() < 1e-6);
+        assert!((chars.score("a b", false) - ln(-0.2 - 0.25 - 2.0 - 0.7)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_binary() {
+        let mut bytes = Vec::new();
+        convert_arpa(ARPA.as_bytes(), &mut bytes).unwrap();
+        let binary = NgramModel::from_binary(bytes.clone(), Unit::Words).unwrap();
+        let arpa = model(Unit::Words);
+        assert_eq!(binary.order(), 2);
+        for word in &["<s>", "a", "b", "</s>", "<unk>", "c"] {
+            assert_eq!(binary.word_id(word), arpa.word_id(word));
+        }
+        // few enough values that quantization keeps them all
+        for text in &["a b", "b a", "a c b", ""] {
+            assert!((binary.score(text, true) - arpa.score(text, true)).abs() < 1e-6);
+        }
+
+        // corrupt offsets are caught when loading rather than when decoding
+        let is_rejected = |bytes: Vec<u8>| {
+            matches!(
+                NgramModel::from_binary(bytes, Unit::Words),
+                Err(LmError::Binary(_))
+            )
+        };
+        let layout = Binary::new(Bytes::Owned(bytes.clone())).unwrap();
+        let corrupt = |offset: usize| {
+            let mut bytes = bytes.clone();
+            bytes[offset..offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+            bytes
+        };
+        assert!(is_rejected(corrupt(layout.vocab_offsets + 4)));
+        assert!(is_rejected(corrupt(layout.vocab_sorted)));
+        assert!(is_rejected(corrupt(layout.levels[1].words)));
+        assert!(is_rejected(corrupt(layout.levels[0].children.unwrap() + 4)));
+
+        bytes.truncate(bytes.len() - 4);
+        assert!(is_rejected(bytes));
+    }
+
+    #[test]
+    fn test_quantization() {
+        let values: Vec<f32> = (0..10000).map(|x| -(x as f32) / 1000.0).collect();
+        let codebook = codebook(&values);
+        for &value in &values {
+            let code = quantize(&codebook, value) as usize;
+            assert!((codebook[code] - value).abs() < 0.03);
+        }
+    }
+
+    #[test]
+    fn test_invalid_arpa() {
+        let arpa = "\\data\\\nngram 1=1\n\\1-grams:\n-1.0\n";
+        match NgramModel::from_arpa(arpa.as_bytes(), Unit::Words) {
+            Err(LmError::Format { line, .. }) => assert_eq!(line, 4),
+            _ => panic!("expected a format error"),
+        }
+
+        let arpa = "\\data\\\nngram 1=18446744073709551615\n\\1-grams:\n-1.0\n";
+        match NgramModel::from_arpa(arpa.as_bytes(), Unit::Words) {
+            Err(LmError::Format { line, .. }) => assert_eq!(line, 4),
+            _ => panic!("expected a format error"),
+        }
+    }
+}
@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+
+use ndarray::{ArrayBase, Data, Ix2};
+
+use crate::scorer::Scorer;
+use crate::search::{BeamSearch, Hypothesis, SearchError};
+
+/// The node of the empty pronunciation.
+const ROOT: usize = 0;
+
+/// The most ways of splitting a labelling into words that are kept track of. Those into the fewest
+/// words are kept, so that short words can't make their number explode.
+const MAX_SEGMENTATIONS: usize = 16;
+
+/// Words and how they are pronounced, stored as a trie over the labels (e.g. phonemes) of their
+/// pronunciations.
+pub struct PronunciationLexicon {
+    words: Vec<String>,
+    /// The child of every node for every label following it.
+    children: HashMap<(usize, usize), usize>,
+    /// The words pronounced as the labels leading to every node.
+    word_ends: Vec<Vec<u32>>,
+}
+
+impl PronunciationLexicon {
+    /// Builds the lexicon from words and their pronunciations, as labels. Words can have several
+    /// pronunciations, and pronunciations can be shared by several words.
+    pub fn new<I>(pronunciations: I) -> Self
+    where
+        I: IntoIterator<Item = (String, Vec<usize>)>,
+    {
+        let mut lexicon = Self {
+            words: Vec::new(),
+            children: HashMap::new(),
+            word_ends: vec![Vec::new()],
+        };
+        let mut ids = HashMap::new();
+        for (word, labels) in pronunciations {
+            let next_id = lexicon.words.len() as u32;
+            let id = *ids.entry(word.clone()).or_insert(next_id);
+            if id == next_id {
+                lexicon.words.push(word);
+            }
+            let mut node = ROOT;
+            for label in labels {
+                let next = lexicon.word_ends.len();
+                node = *lexicon.children.entry((node, label)).or_insert(next);
+                if node == next {
+                    lexicon.word_ends.push(Vec::new());
+                }
+            }
+            if !lexicon.word_ends[node].contains(&id) {
+                lexicon.word_ends[node].push(id);
+            }
+        }
+        lexicon
+    }
+
+    fn child(&self, node: usize, label: usize) -> Option<usize> {
+        self.children.get(&(node, label)).copied()
+    }
+}
+
+/// A word a labelling was split into, with the positions of its first label and of the label after
+/// its last one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Word {
+    id: u32,
+    start: usize,
+    end: usize,
+}
+
+/// A way of splitting a labelling into words.
+#[derive(Clone, Debug)]
+struct Segmentation {
+    /// The words before the last one.
+    words: Vec<Word>,
+    /// The lexicon node the last word has reached so far.
+    node: usize,
+    /// The position of the first label of the last word.
+    start: usize,
+}
+
+impl Segmentation {
+    /// The segmentations where the last word is over at `end`, one for every word pronounced that
+    /// way.
+    fn end_word<'a>(
+        &'a self,
+        lexicon: &'a PronunciationLexicon,
+        end: usize,
+    ) -> impl Iterator<Item = Segmentation> + 'a {
+        lexicon.word_ends[self.node].iter().map(move |&id| {
+            let mut words = self.words.clone();
+            words.push(Word {
+                id,
+                start: self.start,
+                end,
+            });
+            Segmentation {
+                words,
+                node: ROOT,
+                start: end,
+            }
+        })
+    }
+}
+
+/// The ways of splitting a labelling into words of the lexicon, and its number of labels.
+#[derive(Clone, Debug)]
+pub struct PronunciationState {
+    segmentations: Vec<Segmentation>,
+    length: usize,
+}
+
+/// Restricts labellings to the pronunciations of words of a lexicon, keeping track of the words
+/// they could be. Words are separated by `word_delimiter` or, without one, wherever a pronunciation
+/// ends.
+pub struct PronunciationScorer<'a> {
+    lexicon: &'a PronunciationLexicon,
+    word_delimiter: Option<usize>,
+}
+
+impl<'a> PronunciationScorer<'a> {
+    pub fn new(lexicon: &'a PronunciationLexicon, word_delimiter: Option<usize>) -> Self {
+        Self {
+            lexicon,
+            word_delimiter,
+        }
+    }
+
+    /// The words every way of splitting the labelling in `state` into complete words gives.
+    fn complete(&self, state: &PronunciationState) -> Vec<Vec<Word>> {
+        let mut complete = Vec::new();
+        for segmentation in &state.segmentations {
+            if segmentation.node == ROOT {
+                complete.push(segmentation.words.clone());
+            } else {
+                complete.extend(
+                    segmentation
+                        .end_word(self.lexicon, state.length)
+                        .map(|x| x.words),
+                );
+            }
+        }
+        complete
+    }
+}
+
+impl Scorer for PronunciationScorer<'_> {
+    type State = PronunciationState;
+    type Error = SearchError;
+
+    fn initial_state(&mut self) -> Result<PronunciationState, SearchError> {
+        Ok(PronunciationState {
+            segmentations: vec![Segmentation {
+                words: Vec::new(),
+                node: ROOT,
+                start: 0,
+            }],
+            length: 0,
+        })
+    }
+
+    fn extend(
+        &mut self,
+        state: &PronunciationState,
+        label: usize,
+    ) -> Result<(PronunciationState, f32), SearchError> {
+        let lexicon = self.lexicon;
+        let position = state.length;
+        let mut segmentations = Vec::new();
+        for segmentation in &state.segmentations {
+            if Some(label) == self.word_delimiter {
+                if segmentation.node == ROOT {
+                    segmentations.push(segmentation.clone());
+                } else {
+                    segmentations.extend(segmentation.end_word(lexicon, position));
+                }
+                continue;
+            }
+            // the label either continues the last word or, if it could be over, starts a new one
+            let mut ended = Vec::new();
+            if self.word_delimiter.is_none() && segmentation.node != ROOT {
+                ended.extend(segmentation.end_word(lexicon, position));
+            }
+            for x in std::iter::once(segmentation).chain(&ended) {
+                if let Some(node) = lexicon.child(x.node, label) {
+                    segmentations.push(Segmentation {
+                        words: x.words.clone(),
+                        node,
+                        start: if x.node == ROOT { position } else { x.start },
+                    });
+                }
+            }
+        }
+        segmentations.sort_by_key(|x| x.words.len());
+        segmentations.truncate(MAX_SEGMENTATIONS);
+
+        let score = if segmentations.is_empty() {
+            f32::NEG_INFINITY
+        } else {
+            0.0
+        };
+        let state = PronunciationState {
+            segmentations,
+            length: position + 1,
+        };
+        Ok((state, score))
+    }
+
+    fn finish(&mut self, state: &PronunciationState) -> Result<f32, SearchError> {
+        if self.complete(state).is_empty() {
+            Ok(f32::NEG_INFINITY)
+        } else {
+            Ok(0.0)
+        }
+    }
+}
+
+/// CTC prefix beam search decoding to words of a pronunciation lexicon, with labels such as
+/// phonemes making up their pronunciations. See [`crate::search::beam_search`] for the parameters.
+///
+/// Hypotheses are the words, separated by spaces, with the frame every word started at and the
+/// probability of its least likely label. Each is only given once, for the labelling scoring best.
+#[allow(clippy::too_many_arguments)]
+pub fn beam_search<D: Data<Elem = f32>>(
+    probs: &ArrayBase<D, Ix2>,
+    alphabet: &[String],
+    blank: usize,
+    beam_size: usize,
+    beam_cut_threshold: f32,
+    score_cut: f32,
+    envelope: Option<&[(usize, usize)]>,
+    scorer: &mut PronunciationScorer,
+) -> Result<Vec<Hypothesis>, SearchError> {
+    let mut search = BeamSearch::new(
+        alphabet,
+        blank,
+        beam_size,
+        beam_cut_threshold,
+        score_cut,
+        scorer.initial_state()?,
+    );
+    if let Some(envelope) = envelope {
+        search.set_envelope(envelope, probs.nrows())?;
+    }
+    search.advance(probs, scorer)?;
+    search.finish(scorer)?;
+
+    let mut hypotheses: Vec<Hypothesis> = Vec::new();
+    for (state, (_, score, frames, confidences, probability, scorer_score)) in search.labellings() {
+        for words in scorer.complete(state) {
+            let text = words
+                .iter()
+                .map(|x| scorer.lexicon.words[x.id as usize].as_str())
+                .collect::<Vec<_>>()
+                .join(" ");
+            if hypotheses.iter().any(|x| x.0 == text) {
+                continue;
+            }
+            hypotheses.push((
+                text,
+                score,
+                words.iter().map(|x| frames[x.start]).collect(),
+                words
+                    .iter()
+                    .map(|x| {
+                        confidences[x.start..x.end]
+                            .iter()
+                            .copied()
+                            .fold(1.0, f32::min)
+                    })
+                    .collect(),
+                probability,
+                scorer_score,
+            ));
+        }
+    }
+    Ok(hypotheses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array2;
+
+    const T: usize = 1;
+    const UW: usize = 2;
+    const L: usize = 3;
+    const DELIMITER: usize = 4;
+
+    fn lexicon() -> PronunciationLexicon {
+        PronunciationLexicon::new(vec![
+            ("to".to_owned(), vec![T, UW]),
+            ("two".to_owned(), vec![T, UW]),
+            ("tool".to_owned(), vec![T, UW, L]),
+            ("loo".to_owned(), vec![L, UW]),
+            ("loo".to_owned(), vec![L, UW, UW]),
+        ])
+    }
+
+    /// Frames where every label of `labels` in turn is certain, with blanks in between.
+    fn spell(labels: &[usize]) -> Array2<f32> {
+        let mut probs = Array2::from_elem((labels.len() * 2, 5), f32::NEG_INFINITY);
+        for (i, &label) in labels.iter().enumerate() {
+            probs[[2 * i, label]] = 0.0;
+            probs[[2 * i + 1, 0]] = 0.0;
+        }
+        probs
+    }
+
+    /// The hypotheses for `labels` spelled out.
+    fn decode(labels: &[usize], word_delimiter: Option<usize>) -> Vec<Hypothesis> {
+        let alphabet: Vec<String> = ["-", "T", "UW", "L", "|"]
+            .iter()
+            .map(|&x| x.to_owned())
+            .collect();
+        let lexicon = lexicon();
+        let mut scorer = PronunciationScorer::new(&lexicon, word_delimiter);
+        beam_search(
+            &spell(labels),
+            &alphabet,
+            0,
+            10,
+            0.0,
+            f32::INFINITY,
+            None,
+            &mut scorer,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_segmentations() {
+        let result = decode(&[T, UW, L, UW], None);
+        let texts: Vec<&str> = result.iter().map(|x| x.0.as_str()).collect();
+        assert_eq!(texts, vec!["to loo", "two loo"]);
+        assert_eq!(result[0].1, 0.0);
+        assert_eq!(result[0].2, vec![0, 4]);
+        assert_eq!(result[0].3, vec![1.0, 1.0]);
+
+        // "tool" can't be followed by a lone "UW"
+        let result = decode(&[T, UW, L, UW, UW], None);
+        let texts: Vec<&str> = result.iter().map(|x| x.0.as_str()).collect();
+        assert_eq!(texts, vec!["to loo", "two loo"]);
+    }
+
+    #[test]
+    fn test_word_delimiter() {
+        let result = decode(&[T, UW, L, DELIMITER, L, UW], Some(DELIMITER));
+        let texts: Vec<&str> = result.iter().map(|x| x.0.as_str()).collect();
+        assert_eq!(texts, vec!["tool loo"]);
+        assert_eq!(result[0].2, vec![0, 8]);
+
+        // words only end at the delimiter
+        assert!(decode(&[T, UW, L, UW], Some(DELIMITER)).is_empty());
+    }
+}
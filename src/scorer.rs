@@ -0,0 +1,620 @@
+use crate::lexicon::{Lexicon, LEXICON_ROOT};
+use crate::ngram::NgramModel;
+use crate::search::SearchError;
+
+/// Something that scores labellings as the search extends them, one label at a time, e.g. a
+/// language model. What it gives is added to the log probability of the labelling.
+///
+/// The state of every labelling is kept in the suffix tree, so a labelling is only extended once
+/// however many frames and beam entries it appears in.
+pub trait Scorer {
+    /// What the scorer needs to know about a labelling to extend it.
+    type State: Clone;
+    type Error: From<SearchError>;
+
+    /// The state of the empty labelling.
+    fn initial_state(&mut self) -> Result<Self::State, Self::Error>;
+
+    /// The state of the labelling in `state` extended by `label`, and its score for the label. A
+    /// score of negative infinity rules the labelling out.
+    fn extend(
+        &mut self,
+        state: &Self::State,
+        label: usize,
+    ) -> Result<(Self::State, f32), Self::Error>;
+
+    /// Extends several labellings at once, given as their states and new labels, like
+    /// [`Scorer::extend`] does one at a time. The search extends all labellings a frame reaches for
+    /// the first time together.
+    fn extend_batch(
+        &mut self,
+        extensions: &[(&Self::State, usize)],
+    ) -> Result<Vec<(Self::State, f32)>, Self::Error> {
+        extensions
+            .iter()
+            .map(|&(state, label)| self.extend(state, label))
+            .collect()
+    }
+
+    /// The score for the end of the sentence, once the input has ended.
+    fn finish(&mut self, state: &Self::State) -> Result<f32, Self::Error>;
+}
+
+/// A scorer whose scores are multiplied by a weight.
+#[derive(Clone)]
+pub struct Weighted<S> {
+    scorer: S,
+    weight: f32,
+}
+
+impl<S> Weighted<S> {
+    pub fn new(scorer: S, weight: f32) -> Self {
+        Self { scorer, weight }
+    }
+}
+
+impl<S: Scorer> Scorer for Weighted<S> {
+    type State = S::State;
+    type Error = S::Error;
+
+    fn initial_state(&mut self) -> Result<S::State, S::Error> {
+        self.scorer.initial_state()
+    }
+
+    fn extend(&mut self, state: &S::State, label: usize) -> Result<(S::State, f32), S::Error> {
+        let (state, score) = self.scorer.extend(state, label)?;
+        Ok((state, self.weight * score))
+    }
+
+    fn extend_batch(
+        &mut self,
+        extensions: &[(&S::State, usize)],
+    ) -> Result<Vec<(S::State, f32)>, S::Error> {
+        let mut extended = self.scorer.extend_batch(extensions)?;
+        for (_, score) in &mut extended {
+            *score *= self.weight;
+        }
+        Ok(extended)
+    }
+
+    fn finish(&mut self, state: &S::State) -> Result<f32, S::Error> {
+        Ok(self.weight * self.scorer.finish(state)?)
+    }
+}
+
+/// Two scorers whose scores are added up. Pairs can be nested to combine more.
+impl<A, B> Scorer for (A, B)
+where
+    A: Scorer,
+    B: Scorer,
+    A::Error: From<B::Error>,
+{
+    type State = (A::State, B::State);
+    type Error = A::Error;
+
+    fn initial_state(&mut self) -> Result<Self::State, A::Error> {
+        Ok((self.0.initial_state()?, self.1.initial_state()?))
+    }
+
+    fn extend(
+        &mut self,
+        state: &Self::State,
+        label: usize,
+    ) -> Result<(Self::State, f32), A::Error> {
+        let (first, first_score) = self.0.extend(&state.0, label)?;
+        let (second, second_score) = self.1.extend(&state.1, label)?;
+        Ok(((first, second), first_score + second_score))
+    }
+
+    fn extend_batch(
+        &mut self,
+        extensions: &[(&Self::State, usize)],
+    ) -> Result<Vec<(Self::State, f32)>, A::Error> {
+        let first: Vec<(&A::State, usize)> = extensions
+            .iter()
+            .map(|&(state, label)| (&state.0, label))
+            .collect();
+        let second: Vec<(&B::State, usize)> = extensions
+            .iter()
+            .map(|&(state, label)| (&state.1, label))
+            .collect();
+        let first = self.0.extend_batch(&first)?;
+        let second = self.1.extend_batch(&second)?;
+        Ok(first
+            .into_iter()
+            .zip(second)
+            .map(|((first, first_score), (second, second_score))| {
+                ((first, second), first_score + second_score)
+            })
+            .collect())
+    }
+
+    fn finish(&mut self, state: &Self::State) -> Result<f32, A::Error> {
+        Ok(self.0.finish(&state.0)? + self.1.finish(&state.1)?)
+    }
+}
+
+/// A scorer that may not be there, in which case nothing is scored.
+impl<S: Scorer> Scorer for Option<S> {
+    type State = Option<S::State>;
+    type Error = S::Error;
+
+    fn initial_state(&mut self) -> Result<Self::State, S::Error> {
+        match self {
+            Some(scorer) => Ok(Some(scorer.initial_state()?)),
+            None => Ok(None),
+        }
+    }
+
+    fn extend(
+        &mut self,
+        state: &Self::State,
+        label: usize,
+    ) -> Result<(Self::State, f32), S::Error> {
+        match (self, state) {
+            (Some(scorer), Some(state)) => {
+                let (state, score) = scorer.extend(state, label)?;
+                Ok((Some(state), score))
+            }
+            _ => Ok((None, 0.0)),
+        }
+    }
+
+    fn extend_batch(
+        &mut self,
+        extensions: &[(&Self::State, usize)],
+    ) -> Result<Vec<(Self::State, f32)>, S::Error> {
+        let scorer = match self {
+            Some(scorer) => scorer,
+            None => return Ok(extensions.iter().map(|_| (None, 0.0)).collect()),
+        };
+        // states are there exactly when the scorer is
+        let extensions: Vec<(&S::State, usize)> = extensions
+            .iter()
+            .filter_map(|&(state, label)| Some((state.as_ref()?, label)))
+            .collect();
+        Ok(scorer
+            .extend_batch(&extensions)?
+            .into_iter()
+            .map(|(state, score)| (Some(state), score))
+            .collect())
+    }
+
+    fn finish(&mut self, state: &Self::State) -> Result<f32, S::Error> {
+        match (self, state) {
+            (Some(scorer), Some(state)) => scorer.finish(state),
+            _ => Ok(0.0),
+        }
+    }
+}
+
+/// Scores labellings with an n-gram model: every label as it is added or, if there is a
+/// `word_delimiter`, every word once the delimiter completes it (and the last one at the end).
+/// The end of the sentence is scored too.
+#[derive(Clone)]
+pub struct NgramScorer<'a> {
+    model: &'a NgramModel,
+    alphabet: &'a [String],
+    word_delimiter: Option<usize>,
+}
+
+/// The words an n-gram model needs to score the next one, and the text of a word not scored yet.
+#[derive(Clone, Debug)]
+pub struct NgramState {
+    context: Vec<u32>,
+    word: String,
+}
+
+impl<'a> NgramScorer<'a> {
+    pub fn new(
+        model: &'a NgramModel,
+        alphabet: &'a [String],
+        word_delimiter: Option<usize>,
+    ) -> Self {
+        Self {
+            model,
+            alphabet,
+            word_delimiter,
+        }
+    }
+}
+
+impl Scorer for NgramScorer<'_> {
+    type State = NgramState;
+    type Error = SearchError;
+
+    fn initial_state(&mut self) -> Result<NgramState, SearchError> {
+        Ok(NgramState {
+            context: self.model.start(),
+            word: String::new(),
+        })
+    }
+
+    fn extend(
+        &mut self,
+        state: &NgramState,
+        label: usize,
+    ) -> Result<(NgramState, f32), SearchError> {
+        let model = self.model;
+        let mut state = state.clone();
+        let score = match self.word_delimiter {
+            Some(delimiter) if delimiter != label => {
+                state.word.push_str(&self.alphabet[label]);
+                0.0
+            }
+            Some(_) if state.word.is_empty() => 0.0,
+            Some(_) => {
+                let word = model.word_id(&state.word);
+                state.word.clear();
+                model.advance(&mut state.context, word)
+            }
+            None => model
+                .words(&self.alphabet[label])
+                .into_iter()
+                .map(|word| model.advance(&mut state.context, word))
+                .sum(),
+        };
+        Ok((state, score))
+    }
+
+    fn finish(&mut self, state: &NgramState) -> Result<f32, SearchError> {
+        let mut context = state.context.clone();
+        let mut score = 0.0;
+        if !state.word.is_empty() {
+            score += self
+                .model
+                .advance(&mut context, self.model.word_id(&state.word));
+        }
+        Ok(score + self.model.end_of_sentence(&context))
+    }
+}
+
+/// Scores 1 for every label or, if there is a `word_delimiter`, every word, so that weighted it
+/// is an insertion bonus (or penalty).
+#[derive(Clone)]
+pub struct LengthBonus {
+    word_delimiter: Option<usize>,
+}
+
+impl LengthBonus {
+    pub fn new(word_delimiter: Option<usize>) -> Self {
+        Self { word_delimiter }
+    }
+}
+
+impl Scorer for LengthBonus {
+    /// Whether the labelling ends in a word that hasn't been counted yet.
+    type State = bool;
+    type Error = SearchError;
+
+    fn initial_state(&mut self) -> Result<bool, SearchError> {
+        Ok(false)
+    }
+
+    fn extend(&mut self, &in_word: &bool, label: usize) -> Result<(bool, f32), SearchError> {
+        match self.word_delimiter {
+            Some(delimiter) if delimiter != label => Ok((true, 0.0)),
+            Some(_) if in_word => Ok((false, 1.0)),
+            Some(_) => Ok((false, 0.0)),
+            None => Ok((false, 1.0)),
+        }
+    }
+
+    fn finish(&mut self, &in_word: &bool) -> Result<f32, SearchError> {
+        Ok(if in_word { 1.0 } else { 0.0 })
+    }
+}
+
+/// Scores 1 for every occurrence of a hotword (which can be any text, such as a phrase) in the text
+/// of a labelling, so that weighted it favours labellings containing them.
+#[derive(Clone)]
+pub struct HotwordBonus<'a> {
+    alphabet: &'a [String],
+    hotwords: Vec<String>,
+}
+
+impl<'a> HotwordBonus<'a> {
+    pub fn new(alphabet: &'a [String], hotwords: &[String]) -> Self {
+        Self {
+            alphabet,
+            hotwords: hotwords.iter().filter(|x| !x.is_empty()).cloned().collect(),
+        }
+    }
+}
+
+impl Scorer for HotwordBonus<'_> {
+    /// The longest end of the labelling's text that is the start of a hotword.
+    type State = String;
+    type Error = SearchError;
+
+    fn initial_state(&mut self) -> Result<String, SearchError> {
+        Ok(String::new())
+    }
+
+    fn extend(&mut self, state: &String, label: usize) -> Result<(String, f32), SearchError> {
+        let mut partial = state.clone();
+        let mut found = 0;
+        for c in self.alphabet[label].chars() {
+            partial.push(c);
+            // any hotword ending here is a suffix of the longest partial match
+            found += self
+                .hotwords
+                .iter()
+                .filter(|x| partial.ends_with(x.as_str()))
+                .count();
+            while !self.hotwords.iter().any(|x| x.starts_with(&partial)) {
+                match partial.chars().next() {
+                    Some(first) => partial.drain(..first.len_utf8()),
+                    None => break,
+                };
+            }
+        }
+        Ok((partial, found as f32))
+    }
+
+    fn finish(&mut self, _state: &String) -> Result<f32, SearchError> {
+        Ok(0.0)
+    }
+}
+
+/// Restricts labellings to words of a lexicon, separated by `word_delimiter` (without one, the
+/// whole labelling is a single word). Labellings leaving the lexicon are ruled out, or, if there is
+/// an `unknown_word_penalty`, only penalised by it once for every word that isn't in the lexicon.
+#[derive(Clone)]
+pub struct LexiconScorer<'a> {
+    lexicon: &'a Lexicon,
+    alphabet: &'a [String],
+    word_delimiter: Option<usize>,
+    /// The score of an unknown word.
+    unknown_word_score: f32,
+}
+
+impl<'a> LexiconScorer<'a> {
+    pub fn new(
+        lexicon: &'a Lexicon,
+        alphabet: &'a [String],
+        word_delimiter: Option<usize>,
+        unknown_word_penalty: Option<f32>,
+    ) -> Self {
+        Self {
+            lexicon,
+            alphabet,
+            word_delimiter,
+            unknown_word_score: match unknown_word_penalty {
+                Some(penalty) => -penalty,
+                None => f32::NEG_INFINITY,
+            },
+        }
+    }
+}
+
+impl Scorer for LexiconScorer<'_> {
+    /// The lexicon node the last word of the labelling has reached so far, or none if the word has
+    /// left the lexicon (and has been penalised for it).
+    type State = Option<usize>;
+    type Error = SearchError;
+
+    fn initial_state(&mut self) -> Result<Option<usize>, SearchError> {
+        Ok(Some(LEXICON_ROOT))
+    }
+
+    fn extend(
+        &mut self,
+        &state: &Option<usize>,
+        label: usize,
+    ) -> Result<(Option<usize>, f32), SearchError> {
+        if Some(label) == self.word_delimiter {
+            // the word is over, and might only be the start of one from the lexicon
+            let score = match state {
+                Some(node) if node != LEXICON_ROOT && !self.lexicon.is_word(node) => {
+                    self.unknown_word_score
+                }
+                _ => 0.0,
+            };
+            return Ok((Some(LEXICON_ROOT), score));
+        }
+        let mut node = match state {
+            Some(node) => node,
+            None => return Ok((None, 0.0)),
+        };
+        for c in self.alphabet[label].chars() {
+            node = match self.lexicon.child(node, c) {
+                Some(child) => child,
+                None => return Ok((None, self.unknown_word_score)),
+            };
+        }
+        Ok((Some(node), 0.0))
+    }
+
+    fn finish(&mut self, &state: &Option<usize>) -> Result<f32, SearchError> {
+        match state {
+            Some(node) if node != LEXICON_ROOT && !self.lexicon.is_word(node) => {
+                Ok(self.unknown_word_score)
+            }
+            _ => Ok(0.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ngram::tests::ARPA;
+    use crate::ngram::Unit;
+    use crate::search::tests::alphabet;
+
+    /// Extends the initial state by `labels`, returning the scores and the final one.
+    fn run<S: Scorer>(scorer: &mut S, labels: &[usize]) -> (Vec<f32>, f32) {
+        let mut state = scorer.initial_state().ok().unwrap();
+        let mut scores = Vec::new();
+        for &label in labels {
+            let (next, score) = scorer.extend(&state, label).ok().unwrap();
+            state = next;
+            scores.push(score);
+        }
+        let end = scorer.finish(&state).ok().unwrap();
+        (scores, end)
+    }
+
+    #[test]
+    fn test_ngram_scorer() {
+        let ln = |x: f32| x * std::f32::consts::LN_10;
+        let model = NgramModel::from_arpa(ARPA.as_bytes(), Unit::Words).unwrap();
+        let alphabet = alphabet("- ab");
+
+        // "a b", scoring words at the delimiter and the last one at the end
+        let mut scorer = NgramScorer::new(&model, &alphabet, Some(1));
+        let (scores, end) = run(&mut scorer, &[2, 1, 3]);
+        assert_eq!(scores[0], 0.0);
+        assert!((scores[1] - ln(-0.2)).abs() < 1e-6);
+        assert_eq!(scores[2], 0.0);
+        assert!((end - ln(-0.3 - 0.4)).abs() < 1e-6);
+
+        // the same with characters
+        let model = NgramModel::from_arpa(
+            ARPA.as_bytes(),
+            Unit::Characters {
+                space: "<unk>".to_owned(),
+            },
+        )
+        .unwrap();
+        let mut scorer = NgramScorer::new(&model, &alphabet, None);
+        let (scores, end) = run(&mut scorer, &[2, 3]);
+        assert!((scores[0] - ln(-0.2)).abs() < 1e-6);
+        assert!((scores[1] - ln(-0.3)).abs() < 1e-6);
+        assert!((end - ln(-0.4)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_length_bonus() {
+        assert_eq!(
+            run(&mut LengthBonus::new(None), &[2, 1, 3]),
+            (vec![1.0, 1.0, 1.0], 0.0)
+        );
+        // words are counted when complete, and runs of delimiters don't make empty ones
+        assert_eq!(
+            run(&mut LengthBonus::new(Some(1)), &[2, 1, 1, 3]),
+            (vec![0.0, 1.0, 0.0, 0.0], 1.0)
+        );
+    }
+
+    #[test]
+    fn test_hotword_bonus() {
+        let alphabet = alphabet("-abc");
+        let hotwords = vec!["ab".to_owned(), "bc".to_owned(), "abab".to_owned()];
+        let mut scorer = HotwordBonus::new(&alphabet, &hotwords);
+        assert_eq!(run(&mut scorer, &[1, 2, 3]).0, vec![0.0, 1.0, 1.0]);
+        assert_eq!(run(&mut scorer, &[1, 2, 1, 2]).0, vec![0.0, 1.0, 0.0, 2.0]);
+        assert_eq!(run(&mut scorer, &[1, 1, 3, 2]).0, vec![0.0; 4]);
+
+        // hotwords can be split across and within multi-character labels
+        let tokens: Vec<String> = vec!["".into(), "xa".into(), "bcx".into()];
+        let mut scorer = HotwordBonus::new(&tokens, &hotwords);
+        assert_eq!(run(&mut scorer, &[1, 2]).0, vec![0.0, 2.0]);
+    }
+
+    #[test]
+    fn test_combined() {
+        let alphabet = alphabet("-ab");
+        let hotwords = vec!["ab".to_owned()];
+        let mut scorer = (
+            Weighted::new(LengthBonus::new(None), 0.5),
+            Some(Weighted::new(HotwordBonus::new(&alphabet, &hotwords), 2.0)),
+        );
+        assert_eq!(run(&mut scorer, &[1, 2]), (vec![0.5, 2.5], 0.0));
+
+        let state = scorer.initial_state().unwrap();
+        let (state, _) = scorer.extend(&state, 1).unwrap();
+        let extended = scorer.extend_batch(&[(&state, 1), (&state, 2)]).unwrap();
+        let scores: Vec<f32> = extended.iter().map(|(_, score)| *score).collect();
+        assert_eq!(scores, vec![0.5, 2.5]);
+
+        let mut scorer: (Weighted<LengthBonus>, Option<LengthBonus>) =
+            (Weighted::new(LengthBonus::new(None), 0.5), None);
+        assert_eq!(run(&mut scorer, &[1, 2]), (vec![0.5, 0.5], 0.0));
+    }
+
+    #[test]
+    fn test_hotword_search() {
+        let probs = ndarray::array![[0.2f32, 0.5, 0.3]].mapv(f32::ln);
+        let alphabet = alphabet("-ab");
+        let decode = |scorer: &mut Weighted<HotwordBonus>| {
+            crate::search::beam_search(&probs, &alphabet, 0, 10, 0.0, f32::INFINITY, None, scorer)
+                .unwrap()
+        };
+
+        let mut scorer = Weighted::new(HotwordBonus::new(&alphabet, &[]), 1.0);
+        assert_eq!(decode(&mut scorer)[0].0, "a");
+        let hotwords = vec!["b".to_owned()];
+        let mut scorer = Weighted::new(HotwordBonus::new(&alphabet, &hotwords), 1.0);
+        let result = decode(&mut scorer);
+        assert_eq!(result[0].0, "b");
+        assert!((result[0].1 - (0.3f32.ln() + 1.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_lexicon_scorer() {
+        let alphabet: Vec<String> =
+            vec!["-".into(), " ".into(), "a".into(), "b".into(), "ab".into()];
+        let lexicon = Lexicon::new(&["ab", "abb"]);
+        let inf = f32::NEG_INFINITY;
+
+        let mut scorer = LexiconScorer::new(&lexicon, &alphabet, Some(1), None);
+        assert_eq!(run(&mut scorer, &[2, 3, 1, 4, 3]), (vec![0.0; 5], 0.0));
+        assert_eq!(run(&mut scorer, &[2, 1]), (vec![0.0, inf], 0.0));
+        assert_eq!(run(&mut scorer, &[3]), (vec![inf], 0.0));
+        assert_eq!(run(&mut scorer, &[2]), (vec![0.0], inf));
+
+        // unknown words are only penalised once
+        let mut scorer = LexiconScorer::new(&lexicon, &alphabet, Some(1), Some(2.0));
+        assert_eq!(
+            run(&mut scorer, &[3, 3, 1, 1, 2, 1, 4]),
+            (vec![-2.0, 0.0, 0.0, 0.0, 0.0, -2.0, 0.0], 0.0)
+        );
+    }
+
+    #[test]
+    fn test_lexicon_search() {
+        let probs = ndarray::array![[0.2f32, 0.0, 0.5, 0.3]].mapv(f32::ln);
+        let alphabet = alphabet("- ab");
+        let lexicon = Lexicon::new(&["b"]);
+        let decode = |penalty| {
+            let mut scorer = LexiconScorer::new(&lexicon, &alphabet, Some(1), penalty);
+            crate::search::beam_search(
+                &probs,
+                &alphabet,
+                0,
+                10,
+                0.0,
+                f32::INFINITY,
+                None,
+                &mut scorer,
+            )
+            .unwrap()
+        };
+
+        // "a" is ruled out altogether
+        let result = decode(None);
+        let texts: Vec<&str> = result.iter().map(|x| x.0.as_str()).collect();
+        assert_eq!(texts, vec!["b"]);
+        assert_eq!(decode(Some(1.0))[0].0, "b");
+        assert_eq!(decode(Some(0.1))[0].0, "a");
+
+        // with no word left in the beam, nothing is found rather than a non-word
+        let probs = ndarray::array![[0.2f32, 0.0, 0.7, 0.1]].mapv(f32::ln);
+        let lexicon = Lexicon::new(&["ab"]);
+        let mut scorer = LexiconScorer::new(&lexicon, &alphabet, Some(1), None);
+        let result = crate::search::beam_search(
+            &probs,
+            &alphabet,
+            0,
+            1,
+            0.0,
+            f32::INFINITY,
+            None,
+            &mut scorer,
+        )
+        .unwrap();
+        assert!(result.is_empty());
+    }
+}
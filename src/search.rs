@@ -0,0 +1,1004 @@
+use ndarray::{ArrayBase, Data, Ix2};
+
+use crate::scorer::Scorer;
+use crate::tree::{SuffixTree, ROOT_NODE};
+
+#[derive(Clone, Copy, Debug)]
+struct SearchPoint {
+    /// The node search should progress from.
+    node: i32,
+    /// The log probability of the labelling ending in a blank.
+    p_blank: f32,
+    /// The log probability of the labelling ending in its last label.
+    p_nonblank: f32,
+    /// The number of labels in the labelling.
+    length: usize,
+    /// What the scorer adds to the log probability of the labelling.
+    scorer_score: f32,
+}
+
+impl SearchPoint {
+    fn probability(&self) -> f32 {
+        log_sum_exp(self.p_blank, self.p_nonblank)
+    }
+
+    /// The log probability of the labelling with the scorer's score.
+    fn score(&self) -> f32 {
+        self.probability() + self.scorer_score
+    }
+}
+
+/// Computes `ln(exp(a) + exp(b))` without leaving log space.
+pub(crate) fn log_sum_exp(a: f32, b: f32) -> f32 {
+    if a == f32::NEG_INFINITY {
+        return b;
+    }
+    if b == f32::NEG_INFINITY {
+        return a;
+    }
+    let max = a.max(b);
+    max + ((a - max).exp() + (b - max).exp()).ln()
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum SearchError {
+    RanOutOfBeam,
+    IncomparableValues,
+    InvalidEnvelope,
+}
+
+impl std::fmt::Display for SearchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SearchError::RanOutOfBeam => {
+                write!(f, "Ran out of search space (beam_cut_threshold too high)")
+            }
+            SearchError::IncomparableValues => {
+                write!(f, "Failed to compare values (NaNs in input?)")
+            }
+            SearchError::InvalidEnvelope => write!(
+                f,
+                "Invalid envelope values (windows must be non-empty, within the input and \
+                 monotonic)"
+            ),
+        }
+    }
+}
+
+/// A labelling, its score, the frame each label was first emitted at, the probability of the label
+/// at that frame, and the two parts of the score: the log probability of the labelling and what the
+/// scorer adds to it.
+pub type Hypothesis = (String, f32, Vec<usize>, Vec<f32>, f32, f32);
+
+/// When and how confidently the label of a suffix tree node was emitted.
+#[derive(Clone, Copy, Debug)]
+struct Emission {
+    /// The frame the label was first emitted at.
+    frame: usize,
+    /// The probability of the label at that frame.
+    prob: f32,
+}
+
+/// The data of a suffix tree node.
+#[derive(Clone, Debug)]
+struct Node<S> {
+    emission: Emission,
+    /// The scorer state of the labelling ending at the node.
+    state: S,
+    /// The scorer's score for the labelling, summed over its labels.
+    scorer_score: f32,
+}
+
+/// CTC prefix beam search over a sequence of frames that can be fed in several chunks.
+///
+/// Frames are given as log probabilities. The alphabet contains the text of every label, matching
+/// the columns of the frames, with the blank label at position `blank`. See [`beam_search`] for the
+/// meaning of the other parameters.
+///
+/// `S` is the state of the [`Scorer`] used with the search, starting at `initial_state`.
+pub struct BeamSearch<S> {
+    alphabet: Vec<String>,
+    blank: usize,
+    beam_size: usize,
+    log_cut_threshold: f32,
+    score_cut: f32,
+    envelope: Option<Vec<(usize, usize)>>,
+    initial_state: S,
+    suffix_tree: SuffixTree<Node<S>>,
+    beam: Vec<SearchPoint>,
+    next_beam: Vec<SearchPoint>,
+    /// The number of frames seen so far.
+    frame: usize,
+}
+
+impl<S: Clone> BeamSearch<S> {
+    pub fn new(
+        alphabet: &[String],
+        blank: usize,
+        beam_size: usize,
+        beam_cut_threshold: f32,
+        score_cut: f32,
+        initial_state: S,
+    ) -> Self {
+        // suffix tree labels are columns of the frames, so the blank's slot is simply never used
+        let alphabet_size = alphabet.len();
+        Self {
+            alphabet: alphabet.to_vec(),
+            blank,
+            beam_size,
+            log_cut_threshold: beam_cut_threshold.ln(),
+            score_cut,
+            envelope: None,
+            initial_state,
+            suffix_tree: SuffixTree::new(alphabet_size),
+            beam: vec![SearchPoint {
+                node: ROOT_NODE,
+                p_blank: 0.0,
+                p_nonblank: f32::NEG_INFINITY,
+                length: 0,
+                scorer_score: 0.0,
+            }],
+            next_beam: Vec::new(),
+            frame: 0,
+        }
+    }
+
+    /// Restricts the frames labels can be emitted at, see [`beam_search`].
+    ///
+    /// The envelope is checked against `num_frames`, the total number of frames that will be fed.
+    pub fn set_envelope(
+        &mut self,
+        envelope: &[(usize, usize)],
+        num_frames: usize,
+    ) -> Result<(), SearchError> {
+        check_envelope(envelope, num_frames)?;
+        self.envelope = Some(envelope.to_vec());
+        Ok(())
+    }
+
+    /// Forgets all frames fed so far, keeping the parameters.
+    pub fn reset(&mut self) {
+        self.suffix_tree = SuffixTree::new(self.alphabet.len());
+        self.beam = vec![SearchPoint {
+            node: ROOT_NODE,
+            p_blank: 0.0,
+            p_nonblank: f32::NEG_INFINITY,
+            length: 0,
+            scorer_score: 0.0,
+        }];
+        self.frame = 0;
+    }
+
+    /// Feeds the next frames into the search.
+    ///
+    /// If this fails, the search is left in an unspecified state and should not be used further.
+    pub fn advance<D, T>(
+        &mut self,
+        probs: &ArrayBase<D, Ix2>,
+        scorer: &mut T,
+    ) -> Result<(), T::Error>
+    where
+        D: Data<Elem = f32>,
+        T: Scorer<State = S>,
+    {
+        let blank = self.blank;
+        let initial_state = &self.initial_state;
+        let suffix_tree = &mut self.suffix_tree;
+        let log_cut_threshold = self.log_cut_threshold;
+
+        for pr in probs.outer_iter() {
+            let idx = self.frame;
+            self.frame += 1;
+            let beam = &mut self.beam;
+            let next_beam = &mut self.next_beam;
+            next_beam.clear();
+            // labellings reached for the first time, as their entry in `next_beam` (which points at
+            // the parent node until the node is created), last label and its emission
+            let mut new_labellings = Vec::new();
+
+            for &SearchPoint {
+                node,
+                p_blank,
+                p_nonblank,
+                length,
+                scorer_score,
+                ..
+            } in beam.iter()
+            {
+                let tip_label = suffix_tree.label(node);
+                let prob = log_sum_exp(p_blank, p_nonblank);
+                let can_emit = match &self.envelope {
+                    Some(envelope) => matches!(
+                        envelope.get(length),
+                        Some(&(start, end)) if start <= idx && idx < end
+                    ),
+                    None => true,
+                };
+
+                // the labelling stays the same and the frame is a blank
+                if pr[blank] >= log_cut_threshold {
+                    next_beam.push(SearchPoint {
+                        node,
+                        p_blank: prob + pr[blank],
+                        p_nonblank: f32::NEG_INFINITY,
+                        length,
+                        scorer_score,
+                    });
+                }
+
+                for (label, &pr_b) in pr.iter().enumerate() {
+                    if label == blank || pr_b < log_cut_threshold {
+                        continue;
+                    }
+                    let p_extend = if Some(label) == tip_label {
+                        // a repeated label collapses into the tip...
+                        next_beam.push(SearchPoint {
+                            node,
+                            p_blank: f32::NEG_INFINITY,
+                            p_nonblank: p_nonblank + pr_b,
+                            length,
+                            scorer_score,
+                        });
+                        // ...unless there is a blank between them, in which case it is a new label
+                        p_blank + pr_b
+                    } else {
+                        prob + pr_b
+                    };
+                    if p_extend > f32::NEG_INFINITY && can_emit {
+                        let mut extension = SearchPoint {
+                            node,
+                            p_blank: f32::NEG_INFINITY,
+                            p_nonblank: p_extend,
+                            length: length + 1,
+                            scorer_score: 0.0,
+                        };
+                        match suffix_tree.get_child(node, label) {
+                            Some(child) => {
+                                extension.node = child;
+                                extension.scorer_score =
+                                    suffix_tree.get_data_ref(child).unwrap().scorer_score;
+                            }
+                            None => {
+                                let emission = Emission {
+                                    frame: idx,
+                                    prob: pr_b.exp(),
+                                };
+                                new_labellings.push((next_beam.len(), label, emission));
+                            }
+                        }
+                        next_beam.push(extension);
+                    }
+                }
+            }
+            add_nodes(
+                suffix_tree,
+                initial_state,
+                scorer,
+                next_beam,
+                &new_labellings,
+            )?;
+            std::mem::swap(beam, next_beam);
+
+            const DELETE_MARKER: i32 = i32::MIN;
+            beam.sort_by_key(|x| x.node);
+            let mut last_key = DELETE_MARKER;
+            let mut last_key_pos = 0;
+            for i in 0..beam.len() {
+                let beam_item = beam[i];
+                if beam_item.node == last_key {
+                    let merged = &mut beam[last_key_pos];
+                    merged.p_blank = log_sum_exp(merged.p_blank, beam_item.p_blank);
+                    merged.p_nonblank = log_sum_exp(merged.p_nonblank, beam_item.p_nonblank);
+                    beam[i].node = DELETE_MARKER;
+                } else {
+                    last_key_pos = i;
+                    last_key = beam_item.node;
+                }
+            }
+
+            beam.retain(|x| x.node != DELETE_MARKER);
+            drop_ruled_out(beam);
+            sort_beam(beam)?;
+            beam.truncate(self.beam_size);
+            if let Some(best) = beam.first().map(SearchPoint::score) {
+                let score_cut = self.score_cut;
+                beam.retain(|x| x.score() >= best - score_cut);
+            }
+            if beam.is_empty() {
+                // we've run out of beam (probably the threshold is too high)
+                return Err(SearchError::RanOutOfBeam.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds the scorer's final score to every labelling in the beam once all frames have
+    /// been fed. No more frames should be fed afterwards, until the search is reset.
+    pub fn finish<T>(&mut self, scorer: &mut T) -> Result<(), T::Error>
+    where
+        T: Scorer<State = S>,
+    {
+        for x in self.beam.iter_mut() {
+            let state = match self.suffix_tree.get_data_ref(x.node) {
+                Some(data) => &data.state,
+                None => &self.initial_state,
+            };
+            x.scorer_score += scorer.finish(state)?;
+        }
+        drop_ruled_out(&mut self.beam);
+        sort_beam(&mut self.beam)?;
+        Ok(())
+    }
+
+    /// The labellings currently in the beam, best first. Those ruled out (scoring -inf) are left
+    /// out, so this is empty if nothing in the beam is possible.
+    pub fn hypotheses(&self) -> Vec<Hypothesis> {
+        self.beam
+            .iter()
+            .filter(|x| x.node != ROOT_NODE && x.score() > f32::NEG_INFINITY)
+            .map(|x| self.hypothesis(x))
+            .collect()
+    }
+
+    /// The scorer state of every labelling in the beam, best first, together with its hypothesis
+    /// like [`BeamSearch::hypotheses`] gives it.
+    pub fn labellings(&self) -> Vec<(&S, Hypothesis)> {
+        self.beam
+            .iter()
+            .filter(|x| x.score() > f32::NEG_INFINITY)
+            .filter_map(|x| {
+                let data = self.suffix_tree.get_data_ref(x.node)?;
+                Some((&data.state, self.hypothesis(x)))
+            })
+            .collect()
+    }
+
+    fn hypothesis(&self, x: &SearchPoint) -> Hypothesis {
+        let (frames, confidences) = emissions(&self.suffix_tree, x.node);
+        (
+            self.suffix_tree.get_path(x.node, &self.alphabet),
+            x.score(),
+            frames,
+            confidences,
+            x.probability(),
+            x.scorer_score,
+        )
+    }
+}
+
+/// Creates the nodes of the labellings a frame reached for the first time, having the scorer
+/// extend them all at once, and points their beam entries at them.
+///
+/// `new_labellings` holds the position of every labelling's entry in `beam`, which still points at
+/// the parent node, its last label and when that was emitted.
+fn add_nodes<T: Scorer>(
+    suffix_tree: &mut SuffixTree<Node<T::State>>,
+    initial_state: &T::State,
+    scorer: &mut T,
+    beam: &mut [SearchPoint],
+    new_labellings: &[(usize, usize, Emission)],
+) -> Result<(), T::Error> {
+    if new_labellings.is_empty() {
+        return Ok(());
+    }
+    let extensions: Vec<(&T::State, usize)> = new_labellings
+        .iter()
+        .map(
+            |&(entry, label, _)| match suffix_tree.get_data_ref(beam[entry].node) {
+                Some(data) => (&data.state, label),
+                None => (initial_state, label),
+            },
+        )
+        .collect();
+    let extended = scorer.extend_batch(&extensions)?;
+    for (&(entry, label, emission), (state, scorer_score)) in new_labellings.iter().zip(extended) {
+        let parent = beam[entry].node;
+        let scorer_score = match suffix_tree.get_data_ref(parent) {
+            Some(data) => data.scorer_score + scorer_score,
+            None => scorer_score,
+        };
+        beam[entry].node = suffix_tree.add_node(
+            parent,
+            label,
+            Node {
+                emission,
+                state,
+                scorer_score,
+            },
+        );
+        beam[entry].scorer_score = scorer_score;
+    }
+    Ok(())
+}
+
+/// Drops the labellings the scorer ruled out, unless that leaves nothing (in which case the search
+/// carries on with them rather than running out of beam; they are never given as hypotheses).
+fn drop_ruled_out(beam: &mut Vec<SearchPoint>) {
+    if beam.iter().any(|x| x.scorer_score > f32::NEG_INFINITY) {
+        beam.retain(|x| x.scorer_score > f32::NEG_INFINITY);
+    }
+}
+
+/// Sorts beam entries by descending score.
+fn sort_beam(beam: &mut [SearchPoint]) -> Result<(), SearchError> {
+    let mut has_nans = false;
+    beam.sort_unstable_by(|a, b| {
+        (b.score()).partial_cmp(&(a.score())).unwrap_or_else(|| {
+            has_nans = true;
+            std::cmp::Ordering::Equal // don't really care
+        })
+    });
+    if has_nans {
+        return Err(SearchError::IncomparableValues);
+    }
+    Ok(())
+}
+
+/// CTC prefix beam search.
+///
+/// `probs` holds per-frame log probabilities. `alphabet` contains the text of every label, matching
+/// the columns of `probs`, with the blank label at position `blank`. Labels can be
+/// single characters or multi-character tokens. The scores `scorer` gives labellings as they are
+/// extended and at the end are added to their log probabilities.
+///
+/// Labels whose frame probability is below `beam_cut_threshold` (a plain probability, not a log) are
+/// not expanded, and beam entries scoring more than `score_cut` below the best one are dropped.
+///
+/// If an `envelope` is given, the label at position `i` of the labelling can only be emitted at
+/// frames in `envelope[i].0..envelope[i].1`, and labellings can't be longer than the envelope.
+#[allow(clippy::too_many_arguments)]
+pub fn beam_search<D, T>(
+    probs: &ArrayBase<D, Ix2>,
+    alphabet: &[String],
+    blank: usize,
+    beam_size: usize,
+    beam_cut_threshold: f32,
+    score_cut: f32,
+    envelope: Option<&[(usize, usize)]>,
+    scorer: &mut T,
+) -> Result<Vec<Hypothesis>, T::Error>
+where
+    D: Data<Elem = f32>,
+    T: Scorer,
+{
+    let mut search = BeamSearch::new(
+        alphabet,
+        blank,
+        beam_size,
+        beam_cut_threshold,
+        score_cut,
+        scorer.initial_state()?,
+    );
+    if let Some(envelope) = envelope {
+        search.set_envelope(envelope, probs.nrows())?;
+    }
+    search.advance(probs, scorer)?;
+    search.finish(scorer)?;
+    Ok(search.hypotheses())
+}
+
+/// Checks that every window of `envelope` is non-empty and within `num_frames`, and that the windows
+/// don't move backwards.
+fn check_envelope(envelope: &[(usize, usize)], num_frames: usize) -> Result<(), SearchError> {
+    let mut last = (0, 0);
+    for &(start, end) in envelope {
+        if start >= end || end > num_frames || start < last.0 || end < last.1 {
+            return Err(SearchError::InvalidEnvelope);
+        }
+        last = (start, end);
+    }
+    Ok(())
+}
+
+/// The frame each label of the labelling ending at `node` was first emitted at, and the
+/// probability of the label at that frame.
+fn emissions<S>(suffix_tree: &SuffixTree<Node<S>>, node: i32) -> (Vec<usize>, Vec<f32>) {
+    let mut frames = Vec::new();
+    let mut probs = Vec::new();
+    for (_label, Node { emission, .. }) in suffix_tree.iter_from(node) {
+        frames.push(emission.frame);
+        probs.push(emission.prob);
+    }
+    frames.reverse();
+    probs.reverse();
+    (frames, probs)
+}
+
+/// Best path decoding: takes the most likely label of every frame, collapses repeats and removes
+/// blanks (the label at position `blank`). `probs` holds per-frame log probabilities.
+///
+/// Returns the labelling together with the frame each label was emitted at and the probability of
+/// the label at that frame, like [`beam_search`] does.
+pub fn greedy_search<D>(
+    probs: &ArrayBase<D, Ix2>,
+    alphabet: &[String],
+    blank: usize,
+) -> Result<(String, Vec<usize>, Vec<f32>), SearchError>
+where
+    D: Data<Elem = f32>,
+{
+    let mut path = String::new();
+    let mut frames = Vec::new();
+    let mut label_probs = Vec::new();
+    let mut last_label = blank;
+
+    for (idx, pr) in probs.outer_iter().enumerate() {
+        let mut best = blank;
+        for (label, &pr_b) in pr.iter().enumerate() {
+            if pr_b.is_nan() {
+                return Err(SearchError::IncomparableValues);
+            }
+            if pr_b > pr[best] {
+                best = label;
+            }
+        }
+        if best != blank && best != last_label {
+            path.push_str(&alphabet[best]);
+            frames.push(idx);
+            label_probs.push(pr[best].exp());
+        }
+        last_label = best;
+    }
+
+    Ok((path, frames, label_probs))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use ndarray::{array, Array2};
+
+    /// An alphabet of single characters, also used by the scorer tests.
+    pub(crate) fn alphabet(labels: &str) -> Vec<String> {
+        labels.chars().map(String::from).collect()
+    }
+
+    struct NoScorer;
+
+    impl Scorer for NoScorer {
+        type State = ();
+        type Error = SearchError;
+
+        fn initial_state(&mut self) -> Result<(), SearchError> {
+            Ok(())
+        }
+
+        fn extend(&mut self, _state: &(), _label: usize) -> Result<((), f32), SearchError> {
+            Ok(((), 0.0))
+        }
+
+        fn finish(&mut self, _state: &()) -> Result<f32, SearchError> {
+            Ok(0.0)
+        }
+    }
+
+    /// Decodes `probs` over single-character `labels` with the blank first, a beam of 10 and no
+    /// cuts, envelope or scorer.
+    fn decode(probs: &Array2<f32>, labels: &str) -> Result<Vec<Hypothesis>, SearchError> {
+        beam_search(
+            probs,
+            &alphabet(labels),
+            0,
+            10,
+            0.0,
+            f32::INFINITY,
+            None,
+            &mut NoScorer,
+        )
+    }
+
+    #[test]
+    fn test_repeat_needs_blank() {
+        let probs = array![[0.1f32, 0.8, 0.1], [0.8, 0.1, 0.1], [0.1, 0.8, 0.1],];
+        let result = decode(&probs.mapv(f32::ln), "-ab").unwrap();
+        assert_eq!(result[0].0, "aa");
+
+        let probs = array![[0.1f32, 0.8, 0.1], [0.1, 0.8, 0.1], [0.1, 0.8, 0.1],];
+        let result = decode(&probs.mapv(f32::ln), "-ab").unwrap();
+        assert_eq!(result[0].0, "a");
+    }
+
+    #[test]
+    fn test_prefix_probability() {
+        let probs = array![[0.4f32, 0.35, 0.25], [0.4, 0.35, 0.25]];
+        let result = decode(&probs.mapv(f32::ln), "-ab").unwrap();
+        assert_eq!(result[0].0, "a");
+        // "a" = "a-" + "-a" + "aa"
+        let expected = (0.35f32 * 0.4 * 2.0 + 0.35 * 0.35).ln();
+        assert!((result[0].1 - expected).abs() < 1e-5);
+        let b = result.iter().find(|(path, ..)| path == "b").unwrap();
+        let expected = (0.25f32 * 0.4 * 2.0 + 0.25 * 0.25).ln();
+        assert!((b.1 - expected).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_long_input_does_not_underflow() {
+        let probs = Array2::from_shape_fn((2_000, 3), |(i, j)| {
+            if j == 1 + i % 2 {
+                0.98f32.ln()
+            } else {
+                0.01f32.ln()
+            }
+        });
+        let result = beam_search(
+            &probs,
+            &alphabet("-ab"),
+            0,
+            4,
+            0.0,
+            f32::INFINITY,
+            None,
+            &mut NoScorer,
+        )
+        .unwrap();
+        assert!(result[0].0.starts_with("abab"));
+        assert!(result[0].1.is_finite());
+        assert!(result[0].1 < -20.0);
+    }
+
+    #[test]
+    fn test_beam_cut_threshold() {
+        let probs = array![[0.1f32, 0.6, 0.3], [0.7, 0.2, 0.1]].mapv(f32::ln);
+        let result = beam_search(
+            &probs,
+            &alphabet("-ab"),
+            0,
+            10,
+            0.25,
+            f32::INFINITY,
+            None,
+            &mut NoScorer,
+        )
+        .unwrap();
+        let paths: Vec<&str> = result.iter().map(|(path, ..)| path.as_str()).collect();
+        assert_eq!(paths, vec!["a", "b"]);
+
+        let result = beam_search(
+            &probs,
+            &alphabet("-ab"),
+            0,
+            10,
+            0.8,
+            f32::INFINITY,
+            None,
+            &mut NoScorer,
+        );
+        assert!(matches!(result, Err(SearchError::RanOutOfBeam)));
+    }
+
+    #[test]
+    fn test_score_cut() {
+        let probs = array![[0.1f32, 0.6, 0.3], [0.7, 0.2, 0.1]].mapv(f32::ln);
+        let result = beam_search(
+            &probs,
+            &alphabet("-ab"),
+            0,
+            10,
+            0.0,
+            0.5,
+            None,
+            &mut NoScorer,
+        )
+        .unwrap();
+        let paths: Vec<&str> = result.iter().map(|(path, ..)| path.as_str()).collect();
+        assert_eq!(paths, vec!["a"]);
+    }
+
+    #[test]
+    fn test_greedy_search() {
+        let probs = array![
+            [0.1f32, 0.8, 0.1],
+            [0.2, 0.7, 0.1],
+            [0.8, 0.1, 0.1],
+            [0.1, 0.6, 0.3],
+            [0.1, 0.3, 0.6],
+            [0.9, 0.05, 0.05],
+        ]
+        .mapv(f32::ln);
+        let (path, frames, label_probs) = greedy_search(&probs, &alphabet("-ab"), 0).unwrap();
+        assert_eq!(path, "aab");
+        assert_eq!(frames, vec![0, 3, 4]);
+        for (prob, expected) in label_probs.iter().zip(&[0.8, 0.6, 0.6]) {
+            assert!((prob - expected).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_emission_frames() {
+        let probs = array![
+            [1.0f32, 0.0, 0.0],
+            [0.1, 0.8, 0.1],
+            [0.1, 0.8, 0.1],
+            [0.8, 0.1, 0.1],
+            [0.1, 0.1, 0.8],
+        ]
+        .mapv(f32::ln);
+        let result = decode(&probs, "-ab").unwrap();
+        assert_eq!(result[0].0, "ab");
+        assert_eq!(result[0].2, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_confidences() {
+        let probs = array![
+            [1.0f32, 0.0, 0.0],
+            [0.0, 0.7, 0.3],
+            [0.0, 1.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [0.4, 0.0, 0.6],
+        ]
+        .mapv(f32::ln);
+        let result = decode(&probs, "-ab").unwrap();
+        assert_eq!(result[0].0, "ab");
+        assert_eq!(result[0].2, vec![1, 4]);
+        assert_eq!(result[0].3.len(), 2);
+        assert!((result[0].3[0] - 0.7).abs() < 1e-6);
+        assert!((result[0].3[1] - 0.6).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_reemitted_prefix() {
+        // "a" is emitted again at frame 3 after "ab" has branched off it at frame 1
+        let probs = array![
+            [0.5f32, 0.4, 0.1],
+            [0.3, 0.1, 0.6],
+            [0.9, 0.05, 0.05],
+            [0.05, 0.9, 0.05],
+            [0.9, 0.05, 0.05],
+        ];
+        let result = decode(&probs.mapv(f32::ln), "-ab").unwrap();
+        let ab = result.iter().find(|(path, ..)| path == "ab").unwrap();
+        assert_eq!(ab.2, vec![0, 1]);
+        assert!((ab.3[0] - 0.4).abs() < 1e-6);
+        assert!((ab.3[1] - 0.6).abs() < 1e-6);
+        for (path, _, frames, confidences, ..) in &result {
+            assert_eq!(frames.len(), path.len());
+            assert!(frames.windows(2).all(|w| w[0] < w[1]));
+            for ((label, &frame), &confidence) in path.chars().zip(frames).zip(confidences) {
+                let column = "-ab".find(label).unwrap();
+                assert!((confidence - probs[[frame, column]]).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn test_envelope() {
+        let probs = array![
+            [0.1f32, 0.8, 0.1],
+            [0.8, 0.1, 0.1],
+            [0.1, 0.6, 0.3],
+            [0.8, 0.1, 0.1],
+        ]
+        .mapv(f32::ln);
+        let result = decode(&probs, "-ab").unwrap();
+        assert_eq!(result[0].0, "aa");
+
+        // the second label has to be emitted at frame 2 or 3, and there is no room for a third
+        let envelope = [(0, 2), (2, 4)];
+        let result = beam_search(
+            &probs,
+            &alphabet("-ab"),
+            0,
+            10,
+            0.0,
+            f32::INFINITY,
+            Some(&envelope),
+            &mut NoScorer,
+        )
+        .unwrap();
+        assert_eq!(result[0].0, "aa");
+        assert!(result.iter().all(|(path, ..)| path.len() <= 2));
+        assert!(result.iter().all(|(_, _, frames, ..)| frames
+            .iter()
+            .zip(&envelope)
+            .all(|(f, w)| w.0 <= *f && *f < w.1)));
+
+        // the first label can't be emitted at frame 0
+        let envelope = [(1, 3)];
+        let result = beam_search(
+            &probs,
+            &alphabet("-ab"),
+            0,
+            10,
+            0.0,
+            f32::INFINITY,
+            Some(&envelope),
+            &mut NoScorer,
+        )
+        .unwrap();
+        assert_eq!(result[0].0, "a");
+        assert_eq!(result[0].2, vec![1]);
+    }
+
+    #[test]
+    fn test_invalid_envelope() {
+        let probs = array![[0.5f32, 0.5], [0.5, 0.5], [0.5, 0.5]].mapv(f32::ln);
+        for envelope in [
+            vec![(1, 1)],
+            vec![(0, 4)],
+            vec![(1, 2), (0, 3)],
+            vec![(0, 3), (1, 2)],
+        ] {
+            let result = beam_search(
+                &probs,
+                &alphabet("-a"),
+                0,
+                10,
+                0.0,
+                f32::INFINITY,
+                Some(&envelope),
+                &mut NoScorer,
+            );
+            assert!(matches!(result, Err(SearchError::InvalidEnvelope)));
+        }
+    }
+
+    #[test]
+    fn test_chunked_search() {
+        let probs = array![
+            [0.1f32, 0.8, 0.1],
+            [0.8, 0.1, 0.1],
+            [0.1, 0.6, 0.3],
+            [0.8, 0.1, 0.1],
+            [0.2, 0.1, 0.7],
+        ]
+        .mapv(f32::ln);
+        let expected = decode(&probs, "-ab").unwrap();
+
+        let mut search = BeamSearch::new(&alphabet("-ab"), 0, 10, 0.0, f32::INFINITY, ());
+        search
+            .advance(&probs.slice(ndarray::s![..2, ..]), &mut NoScorer)
+            .unwrap();
+        assert_eq!(search.hypotheses()[0].0, "a");
+        search
+            .advance(&probs.slice(ndarray::s![2.., ..]), &mut NoScorer)
+            .unwrap();
+        let result = search.hypotheses();
+        assert_eq!(result, expected);
+        assert_eq!(result[0].0, "aab");
+        assert_eq!(result[0].2, vec![0, 2, 3]);
+    }
+
+    #[test]
+    fn test_non_ascii_alphabet() {
+        let probs = array![
+            [0.1f32, 0.8, 0.05, 0.05],
+            [0.8, 0.1, 0.05, 0.05],
+            [0.1, 0.05, 0.05, 0.8],
+        ]
+        .mapv(f32::ln);
+        let result = decode(&probs, "_жё中").unwrap();
+        assert_eq!(result[0].0, "ж中");
+
+        let (path, _, _) = greedy_search(&probs, &alphabet("_жё中"), 0).unwrap();
+        assert_eq!(path, "ж中");
+    }
+
+    #[test]
+    fn test_token_alphabet() {
+        let probs = array![
+            [0.1f32, 0.8, 0.05, 0.05],
+            [0.8, 0.1, 0.05, 0.05],
+            [0.1, 0.05, 0.05, 0.8],
+            [0.1, 0.05, 0.8, 0.05],
+        ]
+        .mapv(f32::ln);
+        let alphabet: Vec<String> = vec!["".into(), " the".into(), "s".into(), "ing".into()];
+        let result = beam_search(
+            &probs,
+            &alphabet,
+            0,
+            10,
+            0.0,
+            f32::INFINITY,
+            None,
+            &mut NoScorer,
+        )
+        .unwrap();
+        assert_eq!(result[0].0, " theings");
+        assert_eq!(result[0].2, vec![0, 1, 2]);
+
+        let (path, _, _) = greedy_search(&probs, &alphabet, 0).unwrap();
+        assert_eq!(path, " theings");
+    }
+
+    #[test]
+    fn test_blank_last() {
+        // the same input as in test_repeat_needs_blank with the blank moved to the end
+        let probs = array![[0.8f32, 0.1, 0.1], [0.1, 0.1, 0.8], [0.8, 0.1, 0.1]].mapv(f32::ln);
+        let result = beam_search(
+            &probs,
+            &alphabet("ab-"),
+            2,
+            10,
+            0.0,
+            f32::INFINITY,
+            None,
+            &mut NoScorer,
+        )
+        .unwrap();
+        assert_eq!(result[0].0, "aa");
+        assert_eq!(result[0].2, vec![0, 2]);
+
+        let (path, _, _) = greedy_search(&probs, &alphabet("ab-"), 2).unwrap();
+        assert_eq!(path, "aa");
+    }
+
+    /// Keeps the text of every labelling it extends, and dislikes labellings ending in "a".
+    struct TextScorer {
+        alphabet: Vec<String>,
+        extended: Vec<String>,
+        batches: usize,
+    }
+
+    impl Scorer for TextScorer {
+        type State = String;
+        type Error = SearchError;
+
+        fn initial_state(&mut self) -> Result<String, SearchError> {
+            Ok(String::new())
+        }
+
+        fn extend(&mut self, state: &String, label: usize) -> Result<(String, f32), SearchError> {
+            let text = format!("{}{}", state, self.alphabet[label]);
+            self.extended.push(text.clone());
+            Ok((text, 0.0))
+        }
+
+        fn extend_batch(
+            &mut self,
+            extensions: &[(&String, usize)],
+        ) -> Result<Vec<(String, f32)>, SearchError> {
+            self.batches += 1;
+            extensions
+                .iter()
+                .map(|&(state, label)| self.extend(state, label))
+                .collect()
+        }
+
+        fn finish(&mut self, state: &String) -> Result<f32, SearchError> {
+            Ok(if state.ends_with('a') { -5.0 } else { 0.0 })
+        }
+    }
+
+    #[test]
+    fn test_scorer_state() {
+        let probs = array![[0.1f32, 0.1, 0.8], [0.2, 0.6, 0.2]].mapv(f32::ln);
+        let result = decode(&probs, "-ab").unwrap();
+        assert_eq!(result[0].0, "ba");
+
+        let mut scorer = TextScorer {
+            alphabet: alphabet("-ab"),
+            extended: Vec::new(),
+            batches: 0,
+        };
+        let result = beam_search(
+            &probs,
+            &alphabet("-ab"),
+            0,
+            10,
+            0.0,
+            f32::INFINITY,
+            None,
+            &mut scorer,
+        )
+        .unwrap();
+        assert_eq!(result[0].0, "b");
+        // "b" = "bb" + "b-" + "-b"
+        assert!((result[0].1 - 0.34f32.ln()).abs() < 1e-5);
+        let ba = result.iter().find(|(path, ..)| path == "ba").unwrap();
+        assert!((ba.1 - (0.48f32.ln() - 5.0)).abs() < 1e-5);
+        // the log probability and the scorer's score are also given apart
+        assert!((ba.4 - 0.48f32.ln()).abs() < 1e-5);
+        assert_eq!(ba.5, -5.0);
+        // every labelling is extended from its prefix's state, once
+        let mut extended = scorer.extended.clone();
+        extended.sort();
+        extended.dedup();
+        assert_eq!(extended.len(), scorer.extended.len());
+        assert!(scorer.extended.contains(&"ba".to_owned()));
+        // all labellings a frame reaches are extended together
+        assert_eq!(scorer.batches, 2);
+    }
+}
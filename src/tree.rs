@@ -192,15 +192,16 @@ impl<T> SuffixTree<T> {
         }
     }
 
-    pub fn get_path(&self, node: i32, alphabet: &str) -> String {
+    pub fn get_path(&self, node: i32, alphabet: &[String]) -> String {
         if node == ROOT_NODE {
             return String::new();
         }
-        let mut sequence = String::new();
-        for (label, _time) in self.iter_from(node) {
-            sequence.push(alphabet.as_bytes()[label + 1] as char);
-        }
-        sequence.chars().rev().collect()
+        let mut labels: Vec<usize> = self.iter_from_no_data(node).collect();
+        labels.reverse();
+        labels
+            .into_iter()
+            .map(|label| alphabet[label].as_str())
+            .collect()
     }
 }
 